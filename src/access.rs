@@ -0,0 +1,178 @@
+use crate::cidr::{Cidr, CidrTrie};
+use crate::clients::{ClientTable, Idle};
+use crossbeam::sync::ShardedLock;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Order in which the allow and deny lists are evaluated. The first list with a matching entry
+/// decides. An address that matches neither list is accepted only if the allow list is empty.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum AccessOrder {
+    /// Allow entries take precedence over deny entries (deny 10.0.0.0/8, allow 10.1.2.3).
+    AllowDeny,
+    /// Deny entries take precedence over allow entries (allow 10.0.0.0/8, deny 10.6.6.6).
+    DenyAllow,
+}
+
+/// Temporarily denies a client address that opens more than `max_connections` connections, or
+/// that triggers more than `max_failures` read or write failures, within `window`.
+#[derive(Clone, Copy, Debug)]
+pub struct AutoBan {
+    pub window: Duration,
+    pub max_connections: Option<u32>,
+    pub max_failures: Option<u32>,
+    pub ban_duration: Duration,
+}
+
+pub struct AccessList {
+    allow: CidrTrie<()>,
+    // entries expire at the given instant, or never
+    deny: ShardedLock<CidrTrie<Option<Instant>>>,
+    order: AccessOrder,
+    auto_ban: Option<AutoBan>,
+    clients: ClientTable<ClientActivity>,
+}
+
+#[derive(Default)]
+struct ClientActivity {
+    window_end: Option<Instant>,
+    connections: u32,
+    failures: u32,
+}
+
+impl Idle for ClientActivity {
+    fn is_idle(&self, now: Instant) -> bool {
+        // the activity is only kept for the current window
+        self.window_end.map(|it| now > it).unwrap_or(true)
+    }
+}
+
+impl ClientActivity {
+    fn roll(&mut self, now: Instant, window: Duration) {
+        if self.window_end.map(|it| now >= it).unwrap_or(true) {
+            self.window_end = Some(now + window);
+            self.connections = 0;
+            self.failures = 0;
+        }
+    }
+}
+
+impl AccessList {
+    pub fn new(
+        allow: &[Cidr],
+        deny: &[Cidr],
+        order: AccessOrder,
+        auto_ban: Option<AutoBan>,
+        max_tracked_clients: usize,
+    ) -> Self {
+        Self {
+            allow: allow.iter().map(|it| (*it, ())).collect(),
+            deny: ShardedLock::new(deny.iter().map(|it| (*it, None)).collect()),
+            order,
+            auto_ban,
+            clients: ClientTable::new(max_tracked_clients),
+        }
+    }
+    pub fn permits(&self, address: &IpAddr, now: Instant) -> bool {
+        let permitted = match self.order {
+            AccessOrder::AllowDeny => {
+                if self.allow.contains(address) {
+                    true
+                } else if self.is_denied(address, now) {
+                    false
+                } else {
+                    self.allow.is_empty()
+                }
+            }
+            AccessOrder::DenyAllow => {
+                if self.is_denied(address, now) {
+                    false
+                } else if self.allow.contains(address) {
+                    true
+                } else {
+                    self.allow.is_empty()
+                }
+            }
+        };
+        permitted && !self.record_connection(address, now)
+    }
+    /// Counts a read or write failure for the client, and bans it if it has too many.
+    pub fn record_failure(&self, address: &IpAddr, now: Instant) {
+        if let Some(rule) = self.auto_ban {
+            if let Some(max_failures) = rule.max_failures {
+                let exceeded = self.clients.update(address, now, |it| {
+                    it.roll(now, rule.window);
+                    it.failures += 1;
+                    it.failures > max_failures
+                });
+                if exceeded {
+                    self.ban(address, rule.ban_duration, now);
+                }
+            }
+        }
+    }
+    pub fn add_deny(&self, cidr: Cidr, duration: Option<Duration>, now: Instant) {
+        let mut deny = self.deny.write().unwrap();
+        deny.retain(|_, expiry| expiry.map(|it| it > now).unwrap_or(true));
+        deny.insert(cidr, duration.map(|it| now + it));
+    }
+    pub fn remove_deny(&self, cidr: &Cidr) {
+        self.deny.write().unwrap().remove(cidr);
+    }
+    /// Lists the deny entries that are in effect, with the time remaining before they expire.
+    pub fn denied(&self, now: Instant) -> Vec<(Cidr, Option<Duration>)> {
+        let mut entries = vec![];
+        self.deny
+            .write()
+            .unwrap()
+            .retain(|cidr, expiry| match expiry {
+                Some(it) if *it <= now => false,
+                _ => {
+                    entries.push((*cidr, expiry.map(|it| it.duration_since(now))));
+                    true
+                }
+            });
+        entries
+    }
+    fn is_denied(&self, address: &IpAddr, now: Instant) -> bool {
+        self.deny
+            .read()
+            .unwrap()
+            .matches(address)
+            .iter()
+            .any(|(_, expiry)| expiry.map(|it| it > now).unwrap_or(true))
+    }
+    // returns true if the client was banned because of its connection rate
+    fn record_connection(&self, address: &IpAddr, now: Instant) -> bool {
+        if let Some(rule) = self.auto_ban {
+            if let Some(max_connections) = rule.max_connections {
+                let exceeded = self.clients.update(address, now, |it| {
+                    it.roll(now, rule.window);
+                    it.connections += 1;
+                    it.connections > max_connections
+                });
+                if exceeded {
+                    self.ban(address, rule.ban_duration, now);
+                    return true;
+                }
+            }
+        }
+        false
+    }
+    // a deny entry of the address lasting longer, such as a permanent one, is kept
+    fn ban(&self, address: &IpAddr, duration: Duration, now: Instant) {
+        eprintln!("{} => BANNED ({}s)", address, duration.as_secs());
+        let cidr = Cidr::from(*address);
+        let expiry = now + duration;
+        let mut deny = self.deny.write().unwrap();
+        deny.retain(|_, expiry| expiry.map(|it| it > now).unwrap_or(true));
+        match deny.get(&cidr) {
+            Some(None) => {}
+            Some(Some(it)) if *it >= expiry => {}
+            _ => {
+                deny.insert(cidr, Some(expiry));
+            }
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use crate::cidr::Cidr;
+use crate::conf::{Conf, ToSocketAddr};
+use crate::errors::Error;
+use crate::tcp::SocketListener;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+// Line based admin protocol, one command per line:
+//   backend add <address> [<pool>]
+//   backend remove <address>
+//   backend limit <address> <max connections|none>
+//   backend enable <address>
+//   backend disable <address>
+//   deny add <cidr> [<seconds>]
+//   deny remove <cidr>
+//   deny list
+//   sessions
+//   certificates reload
+//   cache
+//   cache purge <key>
+//   cache purge-prefix <prefix>
+// Cache keys are made of the scheme, host, path and query, as in "https://www.example.com/a?b".
+// Each command is answered with its output lines (if any) followed by "OK", or by "ERROR <reason>".
+// Only loopback peers and the configured admin sources are accepted, and a line longer than
+// MAX_LINE_LENGTH closes the connection.
+
+const MAX_LINE_LENGTH: u64 = 4096;
+
+pub async fn bind<B: ToSocketAddr + Sync + Send, C: Conf<B> + Sync>(
+    config: &'static C,
+) -> Result<SocketListener, Error> {
+    config.admin_address().bind().await.map_err(Error::from)
+}
+
+pub async fn admin_loop<B: ToSocketAddr + Sync + Send, C: Conf<B> + Sync>(
+    config: &'static C,
+    listener: SocketListener,
+) -> Result<(), Error> {
+    loop {
+        if let Ok((stream, remote_address)) = listener.accept().await {
+            if !config.accepts_admin(&remote_address) {
+                eprintln!("{} => ADMIN REJECTED", remote_address);
+                continue;
+            }
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                while let Ok(n) = (&mut stream)
+                    .take(MAX_LINE_LENGTH)
+                    .read_line(&mut line)
+                    .await
+                {
+                    if n == 0 {
+                        break;
+                    }
+                    if !line.ends_with('\n') && n as u64 == MAX_LINE_LENGTH {
+                        let _ = stream.write_all(b"ERROR line too long\n").await;
+                        break;
+                    }
+                    let response = match execute(config, line.trim()) {
+                        Ok(mut lines) => {
+                            lines.push("OK".to_string());
+                            lines.join("\n")
+                        }
+                        Err(reason) => format!("ERROR {}", reason),
+                    };
+                    line.clear();
+                    if stream
+                        .write_all(format!("{}\n", response).as_bytes())
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+}
+
+fn execute<B: ToSocketAddr, C: Conf<B>>(config: &C, line: &str) -> Result<Vec<String>, String> {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args.as_slice() {
+        ["backend", "add", address] => {
+            config.add_backend(parse_address(address)?);
+            Ok(vec![])
+        }
+        ["backend", "add", address, pool] => {
+            if config.add_pool_backend(pool, parse_address(address)?) {
+                Ok(vec![])
+            } else {
+                Err(format!("unknown pool: {}", pool))
+            }
+        }
+        ["backend", "remove", address] => {
+            config.remove_backend(parse_address(address)?);
+            Ok(vec![])
+        }
+        ["backend", "enable", address] => {
+            config.set_backend_available(parse_address(address)?, true);
+            Ok(vec![])
+        }
+        ["backend", "disable", address] => {
+            config.set_backend_available(parse_address(address)?, false);
+            Ok(vec![])
+        }
+        ["backend", "limit", address, "none"] => {
+            config.set_backend_max_connections(parse_address(address)?, None);
+            Ok(vec![])
+        }
+        ["backend", "limit", address, max] => {
+            let max: u32 = max.parse().map_err(|_| format!("invalid limit: {}", max))?;
+            config.set_backend_max_connections(parse_address(address)?, Some(max));
+            Ok(vec![])
+        }
+        ["deny", "add", cidr] => {
+            config.add_deny(parse_cidr(cidr)?, None);
+            Ok(vec![])
+        }
+        ["deny", "add", cidr, seconds] => {
+            let seconds: u64 = seconds
+                .parse()
+                .map_err(|_| format!("invalid duration: {}", seconds))?;
+            config.add_deny(parse_cidr(cidr)?, Some(Duration::from_secs(seconds)));
+            Ok(vec![])
+        }
+        ["deny", "remove", cidr] => {
+            config.remove_deny(parse_cidr(cidr)?);
+            Ok(vec![])
+        }
+        ["deny", "list"] => Ok(config
+            .denied()
+            .iter()
+            .map(|(cidr, remaining)| match remaining {
+                Some(it) => format!("{} {}", cidr, it.as_secs()),
+                None => cidr.to_string(),
+            })
+            .collect()),
+        ["sessions"] => {
+            let limit = config.session_limit();
+            Ok(vec![match limit.max_sessions() {
+                Some(max) => format!("{}/{}", limit.active_sessions(), max),
+                None => limit.active_sessions().to_string(),
+            }])
+        }
+        ["certificates", "reload"] => config
+            .load_certificates()
+            .map(|_| vec![])
+            .map_err(|e| e.to_string()),
+        ["cache"] => {
+            let cache = config.cache().ok_or("no cache")?;
+            Ok(vec![format!(
+                "{}/{}",
+                cache.size(),
+                cache.settings().max_size
+            )])
+        }
+        ["cache", "purge", key] => {
+            let cache = config.cache().ok_or("no cache")?;
+            Ok(vec![cache.purge(key).to_string()])
+        }
+        ["cache", "purge-prefix", prefix] => {
+            let cache = config.cache().ok_or("no cache")?;
+            Ok(vec![cache.purge_prefix(prefix).to_string()])
+        }
+        _ => Err(format!("unknown command: {}", line)),
+    }
+}
+
+fn parse_address(address: &str) -> Result<SocketAddr, String> {
+    address
+        .parse()
+        .map_err(|_| format!("invalid address: {}", address))
+}
+
+fn parse_cidr(cidr: &str) -> Result<Cidr, String> {
+    cidr.parse().map_err(|_| format!("invalid cidr: {}", cidr))
+}
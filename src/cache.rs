@@ -0,0 +1,557 @@
+use crate::http::{BoxError, ProxyBody};
+use crate::route::host;
+use bytes::{Bytes, BytesMut};
+use http::header::{
+    AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, DATE, ETAG, EXPIRES, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, LAST_MODIFIED, PRAGMA, SET_COOKIE, VARY,
+};
+use http::{request, response, HeaderMap, HeaderName, HeaderValue, Method, Response, StatusCode};
+use http_body::{Body, Frame, SizeHint};
+use http_body_util::{BodyExt, Empty, Full};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+const MAX_SHARD_COUNT: usize = 16;
+// so that the responses of a shard are still evicted in a meaningful order
+const MIN_SHARD_RESPONSES: usize = 8;
+
+// Statuses that can be stored, given an explicit lifetime.
+const CACHEABLE: [StatusCode; 8] = [
+    StatusCode::OK,
+    StatusCode::NON_AUTHORITATIVE_INFORMATION,
+    StatusCode::NO_CONTENT,
+    StatusCode::MULTIPLE_CHOICES,
+    StatusCode::MOVED_PERMANENTLY,
+    StatusCode::PERMANENT_REDIRECT,
+    StatusCode::NOT_FOUND,
+    StatusCode::GONE,
+];
+
+#[derive(Clone, Debug)]
+pub struct CacheSettings {
+    /// Memory used by the stored responses, beyond which the least recently used ones are evicted.
+    pub max_size: usize,
+    /// Bigger responses aren't stored.
+    pub max_response_size: usize,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self {
+            max_size: 64 * 1024 * 1024,
+            max_response_size: 1024 * 1024,
+        }
+    }
+}
+
+/// Response stored in the cache.
+pub struct Stored {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    received: Instant,
+    /// Age of the response when it was received.
+    age: Duration,
+    lifetime: Duration,
+}
+
+pub enum Lookup {
+    Fresh(Arc<Stored>),
+    /// The response is stale, but the backend can tell if it's still valid.
+    Stale(Arc<Stored>),
+    Miss,
+}
+
+struct Entry {
+    key: String,
+    /// Values of the request headers named by Vary.
+    vary: Vec<(HeaderName, Option<String>)>,
+    response: Arc<Stored>,
+    size: usize,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: HashMap<u64, Entry>,
+    /// Ids of the entries (one for each variant) by key, sorted for purging by prefix.
+    keys: BTreeMap<String, Vec<u64>>,
+    /// Ids of the entries by last use.
+    lru: BTreeMap<u64, u64>,
+    counter: u64,
+    size: usize,
+}
+
+/// Shared cache of the responses to the GET and HEAD requests in HTTP mode, keyed by scheme, host,
+/// path and query. Only the responses with an explicit lifetime (Cache-Control or Expires) are
+/// stored, and the stale ones are revalidated when they have an ETag or Last-Modified header.
+/// The entries are split by key into shards, each with its share of the memory and its own LRU
+/// order, so that concurrent hits rarely contend on the same lock.
+pub struct Cache {
+    settings: CacheSettings,
+    hasher: RandomState,
+    shards: Vec<Mutex<Entries>>,
+    shard_size: usize,
+}
+
+impl Cache {
+    pub fn new(settings: CacheSettings) -> Self {
+        let shard_count = (settings.max_size
+            / std::cmp::max(1, settings.max_response_size * MIN_SHARD_RESPONSES))
+        .clamp(1, MAX_SHARD_COUNT);
+        Self {
+            hasher: RandomState::new(),
+            shards: (0..shard_count)
+                .map(|_| Mutex::new(Entries::default()))
+                .collect(),
+            shard_size: settings.max_size / shard_count,
+            settings,
+        }
+    }
+    pub fn settings(&self) -> &CacheSettings {
+        &self.settings
+    }
+    /// Memory used by the stored responses.
+    pub fn size(&self) -> usize {
+        self.shards.iter().map(|it| it.lock().unwrap().size).sum()
+    }
+    /// Key of the request, if the cache applies to it. `tls` tells whether the client connected
+    /// with TLS, so that the plaintext and TLS listeners of the same host don't share responses.
+    pub fn key(request: &request::Parts, tls: bool) -> Option<String> {
+        if !matches!(request.method, Method::GET | Method::HEAD)
+            || request.headers.contains_key(AUTHORIZATION)
+            || directives(&request.headers).any(|(name, _)| name == "no-store")
+        {
+            return None;
+        }
+        let path_and_query = request
+            .uri
+            .path_and_query()
+            .map(|it| it.as_str())
+            .unwrap_or("/");
+        Some(format!(
+            "{}://{}{}",
+            if tls { "https" } else { "http" },
+            host(request)?,
+            path_and_query
+        ))
+    }
+    fn shard(&self, key: &str) -> &Mutex<Entries> {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+    pub fn lookup(&self, key: &str, request: &request::Parts) -> Lookup {
+        // the client wants a response from the backend
+        let reload = directives(&request.headers).any(|(name, value)| {
+            name == "no-cache" || (name == "max-age" && value.as_deref() == Some("0"))
+        }) || values(&request.headers, PRAGMA).any(|it| it == "no-cache");
+        if reload {
+            return Lookup::Miss;
+        }
+        let mut entries = self.shard(key).lock().unwrap();
+        let Some(id) = entries.find(key, request) else {
+            return Lookup::Miss;
+        };
+        entries.touch(id);
+        let stored = entries.entries[&id].response.clone();
+        if stored.is_fresh(Instant::now()) {
+            Lookup::Fresh(stored)
+        } else if !stored.validators().is_empty() {
+            Lookup::Stale(stored)
+        } else {
+            Lookup::Miss
+        }
+    }
+    /// Stores the response to the request once its body is sent, if it can be.
+    pub fn store(
+        &'static self,
+        key: String,
+        request: &request::Parts,
+        response: &response::Parts,
+        body: ProxyBody,
+    ) -> ProxyBody {
+        if request.method != Method::GET {
+            return body;
+        }
+        let Some(lifetime) = lifetime(response.status, &response.headers) else {
+            return body;
+        };
+        let too_big = body
+            .size_hint()
+            .exact()
+            .map(|it| it > self.settings.max_response_size as u64)
+            .unwrap_or(false);
+        if too_big {
+            return body;
+        }
+        let pending = Pending {
+            key,
+            vary: vary(request, &response.headers),
+            status: response.status,
+            headers: response.headers.clone(),
+            received: Instant::now(),
+            lifetime,
+            data: BytesMut::new(),
+        };
+        Caching {
+            body,
+            cache: self,
+            pending: Some(pending),
+        }
+        .boxed()
+    }
+    /// Updates the stored response with the headers of the backend answer (304) to its
+    /// revalidation.
+    pub fn refresh(
+        &self,
+        key: &str,
+        request: &request::Parts,
+        stored: &Stored,
+        response: &response::Parts,
+    ) -> Arc<Stored> {
+        let mut headers = stored.headers.clone();
+        let mut updated = response.headers.clone();
+        updated.remove(CONTENT_LENGTH);
+        for name in updated.keys() {
+            headers.remove(name);
+        }
+        for (name, value) in updated.iter() {
+            headers.append(name, value.clone());
+        }
+        let refreshed = Arc::new(Stored {
+            status: stored.status,
+            lifetime: lifetime(stored.status, &headers).unwrap_or_default(),
+            age: age(&headers),
+            received: Instant::now(),
+            body: stored.body.clone(),
+            headers,
+        });
+        self.insert(
+            key.to_string(),
+            vary(request, &refreshed.headers),
+            refreshed.clone(),
+        );
+        refreshed
+    }
+    /// Removes the responses with this key, and returns how many there were.
+    pub fn purge(&self, key: &str) -> usize {
+        let mut entries = self.shard(key).lock().unwrap();
+        let ids = entries.keys.get(key).cloned().unwrap_or_default();
+        ids.iter().filter_map(|it| entries.remove(*it)).count()
+    }
+    /// Removes the responses with a key starting with this prefix, and returns how many there were.
+    pub fn purge_prefix(&self, prefix: &str) -> usize {
+        let mut purged = 0;
+        for shard in &self.shards {
+            let mut entries = shard.lock().unwrap();
+            let ids: Vec<u64> = entries
+                .keys
+                .range(prefix.to_string()..)
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .flat_map(|(_, ids)| ids.iter().copied())
+                .collect();
+            purged += ids.iter().filter_map(|it| entries.remove(*it)).count();
+        }
+        purged
+    }
+    fn insert(&self, key: String, vary: Vec<(HeaderName, Option<String>)>, stored: Arc<Stored>) {
+        let size = key.len()
+            + stored.body.len()
+            + stored
+                .headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>();
+        if stored.body.len() > self.settings.max_response_size || size > self.shard_size {
+            return;
+        }
+        let mut entries = self.shard(&key).lock().unwrap();
+        let variant = entries.keys.get(&key).and_then(|ids| {
+            ids.iter()
+                .copied()
+                .find(|it| entries.entries[it].vary == vary)
+        });
+        if let Some(id) = variant {
+            entries.remove(id);
+        }
+        entries.counter += 1;
+        let id = entries.counter;
+        entries.keys.entry(key.clone()).or_default().push(id);
+        entries.lru.insert(id, id);
+        entries.size += size;
+        entries.entries.insert(
+            id,
+            Entry {
+                key,
+                vary,
+                response: stored,
+                size,
+                tick: id,
+            },
+        );
+        while entries.size > self.shard_size {
+            let Some((_, id)) = entries.lru.pop_first() else {
+                break;
+            };
+            entries.remove(id);
+        }
+    }
+}
+
+impl Entries {
+    fn find(&self, key: &str, request: &request::Parts) -> Option<u64> {
+        self.keys.get(key)?.iter().copied().find(|id| {
+            self.entries[id]
+                .vary
+                .iter()
+                .all(|(name, value)| joined(&request.headers, name) == *value)
+        })
+    }
+    fn touch(&mut self, id: u64) {
+        self.counter += 1;
+        let tick = self.counter;
+        if let Some(entry) = self.entries.get_mut(&id) {
+            self.lru.remove(&entry.tick);
+            entry.tick = tick;
+            self.lru.insert(tick, id);
+        }
+    }
+    fn remove(&mut self, id: u64) -> Option<Entry> {
+        let entry = self.entries.remove(&id)?;
+        self.lru.remove(&entry.tick);
+        if let Some(ids) = self.keys.get_mut(&entry.key) {
+            ids.retain(|it| *it != id);
+            if ids.is_empty() {
+                self.keys.remove(&entry.key);
+            }
+        }
+        self.size -= entry.size;
+        Some(entry)
+    }
+}
+
+impl Stored {
+    fn current_age(&self, now: Instant) -> Duration {
+        self.age + now.duration_since(self.received)
+    }
+    pub fn is_fresh(&self, now: Instant) -> bool {
+        self.current_age(now) < self.lifetime
+    }
+    /// Conditional request headers asking the backend whether the response is still valid.
+    pub fn validators(&self) -> Vec<(HeaderName, HeaderValue)> {
+        let mut validators = vec![];
+        if let Some(etag) = self.headers.get(ETAG) {
+            validators.push((IF_NONE_MATCH, etag.clone()));
+        }
+        if let Some(last_modified) = self.headers.get(LAST_MODIFIED) {
+            validators.push((IF_MODIFIED_SINCE, last_modified.clone()));
+        }
+        validators
+    }
+    /// The response for the client, 304 if it has it already.
+    pub fn response(&self, request: &request::Parts) -> Response<ProxyBody> {
+        let mut headers = self.headers.clone();
+        let age = self.current_age(Instant::now()).as_secs();
+        headers.insert(AGE, HeaderValue::from(age));
+        let status = if not_modified(request, &headers) {
+            headers.remove(CONTENT_LENGTH);
+            StatusCode::NOT_MODIFIED
+        } else {
+            self.status
+        };
+        let body = if status == StatusCode::NOT_MODIFIED || request.method == Method::HEAD {
+            Empty::new().map_err(|e| match e {}).boxed()
+        } else {
+            Full::new(self.body.clone()).map_err(|e| match e {}).boxed()
+        };
+        let mut response = Response::new(body);
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        response
+    }
+}
+
+// How long the response stays fresh, if it can be stored.
+fn lifetime(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if !CACHEABLE.contains(&status)
+        || headers.contains_key(SET_COOKIE)
+        || values(headers, VARY).any(|it| it == "*")
+    {
+        return None;
+    }
+    let directives: Vec<(String, Option<String>)> = directives(headers).collect();
+    let has = |name: &str| directives.iter().any(|(it, _)| it == name);
+    let seconds = |name: &str| {
+        directives
+            .iter()
+            .find(|(it, _)| it == name)
+            .and_then(|(_, value)| value.as_deref()?.parse::<u64>().ok())
+            .map(Duration::from_secs)
+    };
+    if has("no-store") || has("private") {
+        return None;
+    }
+    let lifetime = if has("no-cache") {
+        Duration::ZERO
+    } else if let Some(lifetime) = seconds("s-maxage").or_else(|| seconds("max-age")) {
+        lifetime
+    } else {
+        let expires = headers.get(EXPIRES)?;
+        // invalid dates are in the past
+        let expires = date(expires).unwrap_or(SystemTime::UNIX_EPOCH);
+        let date = headers
+            .get(DATE)
+            .and_then(date)
+            .unwrap_or_else(SystemTime::now);
+        expires.duration_since(date).unwrap_or_default()
+    };
+    // a response that is never fresh is only useful if it can be revalidated
+    if lifetime.is_zero() && !headers.contains_key(ETAG) && !headers.contains_key(LAST_MODIFIED) {
+        return None;
+    }
+    Some(lifetime)
+}
+
+fn age(headers: &HeaderMap) -> Duration {
+    headers
+        .get(AGE)
+        .and_then(|it| it.to_str().ok())
+        .and_then(|it| it.parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default()
+}
+
+fn vary(request: &request::Parts, headers: &HeaderMap) -> Vec<(HeaderName, Option<String>)> {
+    values(headers, VARY)
+        .filter_map(|it| HeaderName::from_bytes(it.as_bytes()).ok())
+        .map(|name| {
+            let value = joined(&request.headers, &name);
+            (name, value)
+        })
+        .collect()
+}
+
+// Whether the client already has the response, according to its conditional headers.
+fn not_modified(request: &request::Parts, headers: &HeaderMap) -> bool {
+    if request.headers.contains_key(IF_NONE_MATCH) {
+        let Some(etag) = headers.get(ETAG).and_then(|it| it.to_str().ok()) else {
+            return false;
+        };
+        // weak comparison
+        let etag = etag.trim_start_matches("W/");
+        return values(&request.headers, IF_NONE_MATCH)
+            .any(|it| it == "*" || it.trim_start_matches("W/") == etag);
+    }
+    let since = request.headers.get(IF_MODIFIED_SINCE).and_then(date);
+    let last_modified = headers.get(LAST_MODIFIED).and_then(date);
+    match (since, last_modified) {
+        (Some(since), Some(last_modified)) => last_modified <= since,
+        _ => false,
+    }
+}
+
+fn date(value: &HeaderValue) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.to_str().ok()?).ok()
+}
+
+fn joined(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|it| it.to_str().ok())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+fn values(headers: &HeaderMap, name: HeaderName) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(name)
+        .into_iter()
+        .filter_map(|it| it.to_str().ok())
+        .flat_map(|it| it.split(','))
+        .map(|it| it.trim())
+        .filter(|it| !it.is_empty())
+}
+
+// Cache-Control directives, with the names in lower case and the values unquoted.
+fn directives(headers: &HeaderMap) -> impl Iterator<Item = (String, Option<String>)> + '_ {
+    values(headers, CACHE_CONTROL).map(|it| match it.split_once('=') {
+        Some((name, value)) => (
+            name.trim().to_ascii_lowercase(),
+            Some(value.trim().trim_matches('"').to_string()),
+        ),
+        None => (it.to_ascii_lowercase(), None),
+    })
+}
+
+struct Pending {
+    key: String,
+    vary: Vec<(HeaderName, Option<String>)>,
+    status: StatusCode,
+    headers: HeaderMap,
+    received: Instant,
+    lifetime: Duration,
+    data: BytesMut,
+}
+
+// Response body, stored in the cache once it is complete.
+struct Caching {
+    body: ProxyBody,
+    cache: &'static Cache,
+    pending: Option<Pending>,
+}
+
+impl Caching {
+    fn finish(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            let stored = Stored {
+                status: pending.status,
+                age: age(&pending.headers),
+                headers: pending.headers,
+                body: pending.data.freeze(),
+                received: pending.received,
+                lifetime: pending.lifetime,
+            };
+            self.cache
+                .insert(pending.key, pending.vary, Arc::new(stored));
+        }
+    }
+}
+
+impl Body for Caching {
+    type Data = Bytes;
+    type Error = BoxError;
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let frame = ready!(Pin::new(&mut self.body).poll_frame(cx));
+        let max = self.cache.settings.max_response_size;
+        match frame {
+            Some(Ok(ref frame)) => match (frame.data_ref(), self.pending.as_mut()) {
+                (Some(data), Some(pending)) if pending.data.len() + data.len() <= max => {
+                    pending.data.extend_from_slice(data);
+                }
+                // too big, or with trailers
+                _ => self.pending = None,
+            },
+            Some(Err(_)) => self.pending = None,
+            None => self.finish(),
+        }
+        // the last frame might not be followed by the end of the stream
+        if self.body.is_end_stream() {
+            self.finish();
+        }
+        Poll::Ready(frame)
+    }
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
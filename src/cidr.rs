@@ -0,0 +1,301 @@
+use crate::errors::Error;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix_length: u8,
+}
+
+impl Cidr {
+    pub fn new(address: IpAddr, prefix_length: u8) -> Result<Self, Error> {
+        // An IPv4-mapped IPv6 network (::ffff:a.b.c.d/n with n >= 96) is stored as its IPv4 form.
+        let (address, prefix_length) = match address {
+            IpAddr::V6(v6) if v6.to_ipv4_mapped().is_some() && prefix_length >= 96 => {
+                (normalize(address), prefix_length - 96)
+            }
+            _ => (address, prefix_length),
+        };
+        let bit_length = bit_length(&address);
+        if prefix_length > bit_length {
+            return Err(Error::InvalidCidr(format!("{}/{}", address, prefix_length)));
+        }
+        Ok(Self {
+            address: from_bits(&address, mask(bits(&address), prefix_length, bit_length)),
+            prefix_length,
+        })
+    }
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+    #[allow(dead_code)]
+    pub fn prefix_length(&self) -> u8 {
+        self.prefix_length
+    }
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        let address = normalize(*address);
+        address.is_ipv4() == self.address.is_ipv4()
+            && mask(bits(&address), self.prefix_length, bit_length(&address)) == bits(&self.address)
+    }
+}
+
+impl From<IpAddr> for Cidr {
+    fn from(address: IpAddr) -> Self {
+        let address = normalize(address);
+        Self {
+            prefix_length: bit_length(&address),
+            address,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidCidr(s.to_string());
+        match s.split_once('/') {
+            Some((address, prefix_length)) => Cidr::new(
+                address.parse().map_err(|_| invalid())?,
+                prefix_length.parse().map_err(|_| invalid())?,
+            ),
+            None => Ok(Cidr::from(s.parse::<IpAddr>().map_err(|_| invalid())?)),
+        }
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_length)
+    }
+}
+
+/// Maps IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) to their IPv4 form so that a dual-stack
+/// listener matches the same entries as an IPv4 one.
+pub fn normalize(address: IpAddr) -> IpAddr {
+    address.to_canonical()
+}
+
+fn bit_length(address: &IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn bits(address: &IpAddr) -> u128 {
+    match address {
+        IpAddr::V4(v4) => u32::from(*v4) as u128,
+        IpAddr::V6(v6) => u128::from(*v6),
+    }
+}
+
+fn from_bits(address: &IpAddr, bits: u128) -> IpAddr {
+    match address {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(bits as u32)),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from(bits)),
+    }
+}
+
+fn mask(bits: u128, prefix_length: u8, bit_length: u8) -> u128 {
+    if prefix_length == 0 {
+        0
+    } else {
+        let host_bits = (bit_length - prefix_length) as u32;
+        bits >> host_bits << host_bits
+    }
+}
+
+fn bit(bits: u128, index: u8, bit_length: u8) -> usize {
+    ((bits >> (bit_length - 1 - index)) & 1) as usize
+}
+
+/// Binary prefix trie keyed by CIDR blocks, with separate roots for IPv4 and IPv6.
+pub struct CidrTrie<V> {
+    v4: Node<V>,
+    v6: Node<V>,
+    len: usize,
+}
+
+struct Node<V> {
+    value: Option<V>,
+    children: [Option<Box<Node<V>>>; 2],
+}
+
+impl<V> Node<V> {
+    fn new() -> Self {
+        Self {
+            value: None,
+            children: [None, None],
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.value.is_none() && self.children.iter().all(|it| it.is_none())
+    }
+    fn remove(&mut self, bits: u128, index: u8, prefix_length: u8, bit_length: u8) -> Option<V> {
+        if index == prefix_length {
+            return self.value.take();
+        }
+        let child = &mut self.children[bit(bits, index, bit_length)];
+        let removed = child
+            .as_mut()
+            .and_then(|node| node.remove(bits, index + 1, prefix_length, bit_length));
+        if child.as_ref().map(|it| it.is_empty()).unwrap_or(false) {
+            *child = None;
+        }
+        removed
+    }
+    fn retain<F: FnMut(&Cidr, &mut V) -> bool>(
+        &mut self,
+        address: &IpAddr,
+        bits: u128,
+        depth: u8,
+        f: &mut F,
+    ) -> usize {
+        let mut removed = 0;
+        if let Some(value) = self.value.as_mut() {
+            let cidr = Cidr {
+                address: from_bits(address, bits),
+                prefix_length: depth,
+            };
+            if !f(&cidr, value) {
+                self.value = None;
+                removed += 1;
+            }
+        }
+        let bit_length = bit_length(address);
+        for (i, child) in self.children.iter_mut().enumerate() {
+            if let Some(node) = child.as_mut() {
+                let bits = bits | ((i as u128) << (bit_length - 1 - depth));
+                removed += node.retain(address, bits, depth + 1, f);
+                if node.is_empty() {
+                    *child = None;
+                }
+            }
+        }
+        removed
+    }
+}
+
+impl<V> Default for CidrTrie<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> CidrTrie<V> {
+    pub fn new() -> Self {
+        Self {
+            v4: Node::new(),
+            v6: Node::new(),
+            len: 0,
+        }
+    }
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn insert(&mut self, cidr: Cidr, value: V) -> Option<V> {
+        let bits = bits(&cidr.address);
+        let bit_length = bit_length(&cidr.address);
+        let mut node = self.root_mut(&cidr.address);
+        for index in 0..cidr.prefix_length {
+            node = node.children[bit(bits, index, bit_length)]
+                .get_or_insert_with(|| Box::new(Node::new()));
+        }
+        let previous = node.value.replace(value);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+    pub fn remove(&mut self, cidr: &Cidr) -> Option<V> {
+        let bits = bits(&cidr.address);
+        let bit_length = bit_length(&cidr.address);
+        let removed = self
+            .root_mut(&cidr.address)
+            .remove(bits, 0, cidr.prefix_length, bit_length);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+    #[allow(dead_code)]
+    pub fn get(&self, cidr: &Cidr) -> Option<&V> {
+        let bits = bits(&cidr.address);
+        let bit_length = bit_length(&cidr.address);
+        let mut node = self.root(&cidr.address);
+        for index in 0..cidr.prefix_length {
+            node = node.children[bit(bits, index, bit_length)].as_deref()?;
+        }
+        node.value.as_ref()
+    }
+    /// Returns every entry containing the address, from the shortest prefix to the longest.
+    pub fn matches(&self, address: &IpAddr) -> Vec<(Cidr, &V)> {
+        let address = normalize(*address);
+        let bits = bits(&address);
+        let bit_length = bit_length(&address);
+        let mut found = vec![];
+        let mut node = self.root(&address);
+        let mut index = 0;
+        loop {
+            if let Some(ref value) = node.value {
+                found.push((
+                    Cidr {
+                        address: from_bits(&address, mask(bits, index, bit_length)),
+                        prefix_length: index,
+                    },
+                    value,
+                ));
+            }
+            if index == bit_length {
+                break;
+            }
+            match node.children[bit(bits, index, bit_length)].as_deref() {
+                Some(child) => node = child,
+                None => break,
+            }
+            index += 1;
+        }
+        found
+    }
+    pub fn longest_match(&self, address: &IpAddr) -> Option<(Cidr, &V)> {
+        self.matches(address).pop()
+    }
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        self.longest_match(address).is_some()
+    }
+    /// Keeps only the entries for which the predicate returns true.
+    pub fn retain<F: FnMut(&Cidr, &mut V) -> bool>(&mut self, mut f: F) {
+        let v4 = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        let v6 = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+        let removed = self.v4.retain(&v4, 0, 0, &mut f) + self.v6.retain(&v6, 0, 0, &mut f);
+        self.len -= removed;
+    }
+    fn root(&self, address: &IpAddr) -> &Node<V> {
+        match address {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        }
+    }
+    fn root_mut(&mut self, address: &IpAddr) -> &mut Node<V> {
+        match address {
+            IpAddr::V4(_) => &mut self.v4,
+            IpAddr::V6(_) => &mut self.v6,
+        }
+    }
+}
+
+impl<V> FromIterator<(Cidr, V)> for CidrTrie<V> {
+    fn from_iter<I: IntoIterator<Item = (Cidr, V)>>(iter: I) -> Self {
+        let mut trie = Self::new();
+        for (cidr, value) in iter {
+            trie.insert(cidr, value);
+        }
+        trie
+    }
+}
@@ -0,0 +1,73 @@
+use crate::cidr::normalize;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+const SHARD_COUNT: usize = 64;
+const PURGE_INTERVAL: u32 = 1024;
+
+pub trait Idle {
+    /// Idle entries are back to their default state, dropping them loses nothing.
+    fn is_idle(&self, now: Instant) -> bool;
+    /// Entries that can be dropped to make room when the table is full, at the cost of
+    /// forgetting some of the client history.
+    fn is_evictable(&self) -> bool {
+        true
+    }
+}
+
+/// Per client address state, split into shards so that concurrent accepts for different clients
+/// rarely contend on the same lock. Idle entries are purged every thousand updates of a shard,
+/// and evictable entries are dropped in random order when a shard is full.
+/// IPv4-mapped IPv6 addresses share the entry of their IPv4 form.
+pub struct ClientTable<V> {
+    hasher: RandomState,
+    shards: Vec<Shard<V>>,
+    shard_capacity: usize,
+}
+
+struct Shard<V> {
+    entries: Mutex<HashMap<IpAddr, V>>,
+    updates: AtomicU32,
+}
+
+impl<V: Default + Idle> ClientTable<V> {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            hasher: RandomState::new(),
+            shards: (0..SHARD_COUNT)
+                .map(|_| Shard {
+                    entries: Mutex::new(HashMap::new()),
+                    updates: AtomicU32::new(0),
+                })
+                .collect(),
+            shard_capacity: std::cmp::max(1, max_entries / SHARD_COUNT),
+        }
+    }
+    pub fn update<R, F: FnOnce(&mut V) -> R>(&self, address: &IpAddr, now: Instant, f: F) -> R {
+        let address = normalize(*address);
+        let shard = &self.shards[self.hasher.hash_one(address) as usize % SHARD_COUNT];
+        let mut entries = shard.entries.lock().unwrap();
+        if shard.updates.fetch_add(1, Ordering::Relaxed) % PURGE_INTERVAL == PURGE_INTERVAL - 1 {
+            entries.retain(|_, it| !it.is_idle(now));
+        }
+        if entries.len() >= self.shard_capacity && !entries.contains_key(&address) {
+            entries.retain(|_, it| !it.is_idle(now));
+            // evict down to 7/8 of the capacity so that this doesn't run for every new client
+            let mut excess = (entries.len() + 1).saturating_sub(self.shard_capacity * 7 / 8);
+            entries.retain(|_, it| {
+                if excess > 0 && it.is_evictable() {
+                    excess -= 1;
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        f(entries.entry(address).or_default())
+    }
+}
@@ -0,0 +1,241 @@
+use crate::http::{BoxError, ProxyBody};
+use brotli::CompressorWriter;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use http::header::{
+    ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY,
+};
+use http::{request, response, HeaderMap, HeaderValue, Method, StatusCode};
+use http_body::{Body, Frame};
+use http_body_util::BodyExt;
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Brotli => "br",
+        }
+    }
+}
+
+/// Compression of the responses in HTTP mode, with an encoding accepted by the client. Responses
+/// that are already encoded aren't compressed again.
+#[derive(Clone, Debug)]
+pub struct CompressionSettings {
+    /// In order of preference, for the encodings that the client accepts equally.
+    pub encodings: Vec<Encoding>,
+    /// Media types, without parameters. "text/*" matches all the text types.
+    pub content_types: Vec<String>,
+    /// Smaller responses aren't compressed, the ones of unknown size are.
+    pub min_size: u64,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        Self {
+            encodings: vec![Encoding::Brotli, Encoding::Gzip],
+            content_types: [
+                "text/*",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .iter()
+            .map(|it| it.to_string())
+            .collect(),
+            min_size: 1024,
+        }
+    }
+}
+
+impl CompressionSettings {
+    /// The encoding of the response to the request, if it should be compressed.
+    pub fn encoding(
+        &self,
+        request: &request::Parts,
+        response: &response::Parts,
+    ) -> Option<Encoding> {
+        let headers = &response.headers;
+        if request.method == Method::HEAD
+            || response.status != StatusCode::OK
+            || headers.contains_key(CONTENT_ENCODING)
+            || values(headers, CACHE_CONTROL).any(|it| it.eq_ignore_ascii_case("no-transform"))
+        {
+            return None;
+        }
+        let size = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|it| it.to_str().ok())
+            .and_then(|it| it.parse::<u64>().ok());
+        if size.map(|it| it < self.min_size).unwrap_or(false) {
+            return None;
+        }
+        let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+        let content_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let allowed = self
+            .content_types
+            .iter()
+            .any(|it| match it.strip_suffix('*') {
+                Some(prefix) => content_type.starts_with(prefix),
+                None => *it == content_type,
+            });
+        if !allowed {
+            return None;
+        }
+        self.accepted(&request.headers)
+    }
+    // The encoding with the highest quality in Accept-Encoding.
+    fn accepted(&self, headers: &HeaderMap) -> Option<Encoding> {
+        let accepted: Vec<(&str, f32)> = values(headers, ACCEPT_ENCODING)
+            .map(|it| match it.split_once(';') {
+                Some((name, params)) => {
+                    let quality = params
+                        .split(';')
+                        .filter_map(|it| it.trim().strip_prefix("q="))
+                        .find_map(|it| it.trim().parse::<f32>().ok())
+                        .unwrap_or(1.0);
+                    (name.trim(), quality)
+                }
+                None => (it, 1.0),
+            })
+            .collect();
+        let quality = |encoding: &Encoding| {
+            accepted
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(encoding.name()))
+                .or_else(|| accepted.iter().find(|(name, _)| *name == "*"))
+                .map(|(_, quality)| *quality)
+                .unwrap_or(0.0)
+        };
+        let mut best: Option<(Encoding, f32)> = None;
+        for encoding in &self.encodings {
+            let quality = quality(encoding);
+            if quality > 0.0 && best.map(|(_, it)| quality > it).unwrap_or(true) {
+                best = Some((*encoding, quality));
+            }
+        }
+        best.map(|(it, _)| it)
+    }
+}
+
+/// Compresses the body of the response, updating its headers.
+pub fn compress(parts: &mut response::Parts, body: ProxyBody, encoding: Encoding) -> ProxyBody {
+    let headers = &mut parts.headers;
+    headers.remove(CONTENT_LENGTH);
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    // the compressed body isn't byte for byte the same anymore
+    if let Some(etag) = headers.get(ETAG).and_then(|it| it.to_str().ok()) {
+        if !etag.starts_with("W/") {
+            if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                headers.insert(ETAG, weak);
+            }
+        }
+    }
+    let encoder = match encoding {
+        Encoding::Gzip => Encoder::Gzip(GzEncoder::new(vec![], flate2::Compression::default())),
+        Encoding::Brotli => Encoder::Brotli(Box::new(CompressorWriter::new(vec![], 4096, 5, 22))),
+    };
+    Compressed {
+        body,
+        encoder: Some(encoder),
+        trailers: None,
+    }
+    .boxed()
+}
+
+fn values(headers: &HeaderMap, name: http::HeaderName) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(name)
+        .into_iter()
+        .filter_map(|it| it.to_str().ok())
+        .flat_map(|it| it.split(','))
+        .map(|it| it.trim())
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    // Compresses the chunk, and returns what is ready to be sent.
+    fn write(&mut self, data: &[u8]) -> std::io::Result<Bytes> {
+        let output = match self {
+            Self::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            Self::Brotli(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+        };
+        Ok(Bytes::from(std::mem::take(output)))
+    }
+    fn finish(self) -> std::io::Result<Bytes> {
+        let output = match self {
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Brotli(encoder) => encoder.into_inner(),
+        };
+        Ok(Bytes::from(output))
+    }
+}
+
+// Compresses the chunks of the body as they come, without waiting for the next ones.
+struct Compressed {
+    body: ProxyBody,
+    encoder: Option<Encoder>,
+    // sent once the end of the compressed data is
+    trailers: Option<HeaderMap>,
+}
+
+impl Body for Compressed {
+    type Data = Bytes;
+    type Error = BoxError;
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = &mut *self;
+        loop {
+            let Some(encoder) = this.encoder.as_mut() else {
+                return Poll::Ready(this.trailers.take().map(|it| Ok(Frame::trailers(it))));
+            };
+            let data = match ready!(Pin::new(&mut this.body).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => encoder.write(&data)?,
+                    Err(frame) => {
+                        this.trailers = frame.into_trailers().ok();
+                        this.encoder.take().unwrap().finish()?
+                    }
+                },
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => this.encoder.take().unwrap().finish()?,
+            };
+            if !data.is_empty() {
+                return Poll::Ready(Some(Ok(Frame::data(data))));
+            }
+        }
+    }
+    fn is_end_stream(&self) -> bool {
+        self.encoder.is_none() && self.trailers.is_none()
+    }
+}
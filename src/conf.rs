@@ -1,7 +1,27 @@
-use crossbeam::sync::ShardedLock;
-use std::collections::HashSet;
-use std::net::SocketAddr;
-use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use crate::access::{AccessList, AccessOrder, AutoBan};
+use crate::cache::{Cache, CacheSettings};
+use crate::cidr::{normalize, Cidr, CidrTrie};
+use crate::compression::CompressionSettings;
+use crate::errors::Error;
+use crate::failure::{ErrorResponse, ErrorResponseSettings, Failure};
+use crate::forwarded::ForwardedHeaders;
+use crate::headers::HeaderRule;
+use crate::http::IdleConnections;
+use crate::limits::{ClientLimits, OverloadPolicy, RateLimit, SessionLimit};
+use crate::pool::{Backend, BackendSettings, Pool, Unavailable};
+use crate::proxy::ProxyProtocol;
+use crate::retry::{Retries, RetrySettings};
+use crate::rewrite::UrlRule;
+use crate::route::HttpRoute;
+use crate::sni::{ClientHello, HostMap};
+use crate::sticky::StickyCookie;
+use crate::tls::{BackendTls, BackendTlsSettings, Tls, TlsCertificate, TlsSettings, TlsVersion};
+use http::request::Parts;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -12,21 +32,118 @@ pub enum BindAddress {
     TcpSocket(SocketAddr),
 }
 
+/// How the listener handles the client connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// The bytes are copied as they are between the client and the backend.
+    Tcp,
+    /// HTTP/1.1 and HTTP/2 (negotiated with ALPN, or with prior knowledge) are served, each
+    /// request or HTTP/2 stream being routed and balanced on its own.
+    Http,
+}
+
+/// What is known about the client when selecting a backend.
+#[derive(Clone, Copy, Default)]
+pub struct Metadata<'a> {
+    pub client_hello: Option<&'a ClientHello>,
+    /// The request being routed, in HTTP mode.
+    pub request: Option<&'a Parts>,
+}
+
 pub trait ToSocketAddr {
     fn address(&self) -> &SocketAddr;
+    /// Header to send before the client data, if the backend expects the PROXY protocol.
+    fn proxy_protocol(&self) -> Option<&ProxyProtocol> {
+        None
+    }
+    /// TLS client for the connection, if the backend expects TLS.
+    fn tls(&self) -> Option<&BackendTls> {
+        None
+    }
+    /// Whether the HTTP requests are sent with HTTP/2.
+    fn http2(&self) -> bool {
+        false
+    }
+    /// Connections to reuse for the HTTP requests, shared by all the clients.
+    fn idle_connections(&self) -> Option<&IdleConnections> {
+        None
+    }
 }
 
 pub trait Conf<T: ToSocketAddr> {
-    type Trace: Copy + Send;
+    type Trace: Copy + Send + Sync + Unpin + 'static;
     fn bind_address(&self) -> &BindAddress;
     fn admin_address(&self) -> &BindAddress;
+    /// Whether this peer may use the admin protocol. Loopback peers always can.
+    fn accepts_admin(&self, peer_address: &SocketAddr) -> bool;
+    fn session_limit(&self) -> &SessionLimit;
+    fn protocol(&self) -> Protocol;
+    /// TLS termination, when the listener doesn't accept plaintext connections.
+    fn tls(&self) -> Option<&Tls>;
+    /// Reads the certificate files of the listener and of the backend pools. They are only
+    /// swapped in if they all load, new handshakes then use them.
+    fn load_certificates(&self) -> Result<(), Error>;
+    /// Reads the body files of the error responses.
+    fn load_error_responses(&self) -> Result<(), Error>;
+    /// Response sent for a request that failed this way, in HTTP mode, instead of an empty one.
+    fn error_response(&self, failure: Failure) -> Option<&ErrorResponse>;
+    fn certificate_paths(&self) -> Vec<&Path>;
+    /// How often the certificate files are checked for changes, if they are watched.
+    fn certificate_check_interval(&self) -> Option<Duration>;
+    /// Whether connections from this peer start with a PROXY protocol header carrying the
+    /// address of the actual client.
+    fn expects_proxy_protocol(&self, peer_address: &SocketAddr) -> bool;
+    /// Whether the ClientHello of connections that are not terminated is inspected, so that
+    /// `select` can route them by server name.
+    fn routes_by_server_name(&self) -> bool;
+    /// Headers telling the backends about the client, in HTTP mode.
+    fn forwarded_headers(&self) -> ForwardedHeaders;
+    /// Whether the forwarded headers sent by this client are kept and appended to.
+    fn trusts_forwarded_headers(&self, remote_address: &SocketAddr) -> bool;
+    /// Header rules matching the request, the global ones first and then the ones of its route.
+    fn header_rules(&self, remote_address: &SocketAddr, request: &Parts) -> Vec<&HeaderRule>;
+    /// Redirect and rewrite rules for the request, the global ones first and then the ones of its
+    /// route. A rewritten request is routed again by `select`.
+    fn url_rules(&self, request: &Parts) -> Vec<&UrlRule>;
     fn accept(&self, remote_address: &SocketAddr) -> Option<Self::Trace>;
-    fn select(&self, remote_address: &SocketAddr, trace: Self::Trace) -> Option<T>;
+    fn select(
+        &self,
+        remote_address: &SocketAddr,
+        metadata: Metadata<'_>,
+        trace: Self::Trace,
+    ) -> impl Future<Output = Result<T, Unavailable>> + Send;
+    /// Reserves a backend for a retry of the request, among the ones that `select` could have
+    /// returned except the excluded ones. Doesn't wait if none is available.
+    fn select_other(&self, metadata: Metadata<'_>, excluded: &[SocketAddr]) -> Option<T>;
+    /// Retries of the requests that failed, in HTTP mode.
+    fn retries(&self) -> Option<&Retries>;
+    fn compression(&self) -> Option<&CompressionSettings>;
+    /// Responses stored to answer the next requests without a backend, in HTTP mode.
+    fn cache(&self) -> Option<&Cache>;
+    /// Set-Cookie header value for the response, when the client should stick to the backend
+    /// selected for its request (HTTP mode only).
+    fn sticky_cookie(&self, request: &Parts, backend: &T) -> Option<String>;
+    /// Called once an accepted connection is closed, whatever the outcome.
+    fn release(&self, remote_address: &SocketAddr, trace: Self::Trace);
     fn connection_timeout(&self) -> Option<Duration>;
     fn read_timeout(&self) -> Option<Duration>;
     fn write_timeout(&self) -> Option<Duration>;
+    /// Closes upgraded HTTP connections (WebSocket...) when nothing was sent either way for so long.
+    fn upgrade_idle_timeout(&self) -> Option<Duration>;
     fn add_backend(&self, backend_address: SocketAddr);
+    /// Returns false if there is no pool with that name.
+    fn add_pool_backend(&self, pool: &str, backend_address: SocketAddr) -> bool;
+    /// Removes the backend from every pool.
     fn remove_backend(&self, backend_address: SocketAddr);
+    fn set_backend_max_connections(
+        &self,
+        backend_address: SocketAddr,
+        max_connections: Option<u32>,
+    );
+    fn set_backend_available(&self, backend_address: SocketAddr, available: bool);
+    fn add_deny(&self, cidr: Cidr, duration: Option<Duration>);
+    fn remove_deny(&self, cidr: Cidr);
+    fn denied(&self) -> Vec<(Cidr, Option<Duration>)>;
     fn record_success(
         &self,
         remote_address: &SocketAddr,
@@ -35,6 +152,9 @@ pub trait Conf<T: ToSocketAddr> {
         response_size: u64,
         trace: Self::Trace,
     );
+    fn record_no_backend(&self, remote_address: &SocketAddr, trace: Self::Trace);
+    fn record_queue_full(&self, remote_address: &SocketAddr, trace: Self::Trace);
+    fn record_queue_timeout(&self, remote_address: &SocketAddr, trace: Self::Trace);
     fn record_connection_failure(
         &self,
         remote_address: &SocketAddr,
@@ -77,26 +197,104 @@ pub trait Conf<T: ToSocketAddr> {
         error: std::io::Error,
         trace: Self::Trace,
     );
+    /// Gives back the backend of a request dropped before its outcome was known, such as a stream
+    /// reset or a client gone before the response. Not counted as a backend failure.
+    fn record_cancelled(&self, remote_address: &SocketAddr, backend_address: T, trace: Self::Trace);
+    /// A failure caused by the client itself, such as a failed handshake, a protocol error or a
+    /// broken connection, counted towards its auto-ban. Backend failures are not.
+    fn record_client_failure(&self, remote_address: &SocketAddr);
 }
 
 pub struct ConfBuilder {
     bind_address: BindAddress,
     admin_address: BindAddress,
+    admin_sources: Vec<Cidr>,
     connection_timeout: Option<Duration>,
     read_timeout: Option<Duration>,
     write_timeout: Option<Duration>,
-    blacklist: HashSet<SocketAddr>,
+    upgrade_idle_timeout: Option<Duration>,
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    access_order: AccessOrder,
+    auto_ban: Option<AutoBan>,
+    max_connections_per_client: Option<u32>,
+    rate_limit_per_client: Option<RateLimit>,
+    rate_limit_per_subnet: Option<RateLimit>,
+    client_limit_exemptions: Vec<Cidr>,
+    max_tracked_clients: usize,
+    max_sessions: Option<usize>,
+    overload_policy: OverloadPolicy,
+    max_connections_per_backend: Option<u32>,
+    max_queue_length: usize,
+    queue_timeout: Duration,
+    no_backend_grace_period: Option<Duration>,
+    proxy_protocol: Vec<(Option<String>, ProxyProtocol)>,
+    proxy_protocol_sources: Vec<Cidr>,
+    tls: TlsSettings,
+    server_name_routes: Vec<(String, String)>,
+    backend_tls: Vec<(Option<String>, BackendTlsSettings)>,
+    backend_http2: Vec<Option<String>>,
+    certificate_check_interval: Option<Duration>,
+    protocol: Protocol,
+    http_routes: Vec<HttpRoute>,
+    header_rules: Vec<HeaderRule>,
+    url_rules: Vec<UrlRule>,
+    forwarded_headers: ForwardedHeaders,
+    forwarded_headers_sources: Vec<Cidr>,
+    sticky_cookie: Option<StickyCookie>,
+    retries: Option<RetrySettings>,
+    compression: Option<CompressionSettings>,
+    cache: Option<CacheSettings>,
+    error_responses: Vec<(Failure, ErrorResponseSettings)>,
+    max_idle_connections_per_backend: usize,
+    idle_connection_timeout: Option<Duration>,
 }
 
 impl ConfBuilder {
     pub fn new(bind_address: BindAddress) -> Self {
         ConfBuilder {
             admin_address: Self::admin_address_from(&bind_address),
+            admin_sources: vec![],
             bind_address,
             connection_timeout: Some(Duration::from_millis(5_000)),
             read_timeout: Some(Duration::from_millis(30_000)),
             write_timeout: Some(Duration::from_millis(120_000)),
-            blacklist: HashSet::new(),
+            upgrade_idle_timeout: Some(Duration::from_millis(3_600_000)),
+            allow: vec![],
+            deny: vec![],
+            access_order: AccessOrder::DenyAllow,
+            auto_ban: None,
+            max_connections_per_client: None,
+            rate_limit_per_client: None,
+            rate_limit_per_subnet: None,
+            client_limit_exemptions: vec![],
+            max_tracked_clients: 1_000_000,
+            max_sessions: None,
+            overload_policy: OverloadPolicy::Pause,
+            max_connections_per_backend: None,
+            max_queue_length: 1024,
+            queue_timeout: Duration::from_millis(10_000),
+            no_backend_grace_period: None,
+            proxy_protocol: vec![],
+            proxy_protocol_sources: vec![],
+            tls: TlsSettings::default(),
+            server_name_routes: vec![],
+            backend_tls: vec![],
+            backend_http2: vec![],
+            certificate_check_interval: Some(Duration::from_millis(60_000)),
+            protocol: Protocol::Tcp,
+            http_routes: vec![],
+            header_rules: vec![],
+            url_rules: vec![],
+            forwarded_headers: ForwardedHeaders::default(),
+            forwarded_headers_sources: vec![],
+            sticky_cookie: None,
+            retries: None,
+            compression: None,
+            cache: None,
+            error_responses: vec![],
+            max_idle_connections_per_backend: 32,
+            idle_connection_timeout: Some(Duration::from_millis(30_000)),
         }
     }
     #[allow(dead_code)]
@@ -104,6 +302,12 @@ impl ConfBuilder {
         self.admin_address = admin_address;
         self
     }
+    /// Lets these peers use the admin protocol, in addition to the loopback ones.
+    #[allow(dead_code)]
+    pub fn admin_source(&mut self, source: Cidr) -> &mut Self {
+        self.admin_sources.push(source);
+        self
+    }
     #[allow(dead_code)]
     pub fn no_connection_timeout(&mut self) -> &mut Self {
         self.connection_timeout = None;
@@ -135,31 +339,404 @@ impl ConfBuilder {
         self
     }
     #[allow(dead_code)]
-    pub fn blacklist(&mut self, remote_address: SocketAddr) -> &mut Self {
-        self.blacklist.insert(remote_address);
+    pub fn no_upgrade_idle_timeout(&mut self) -> &mut Self {
+        self.upgrade_idle_timeout = None;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn upgrade_idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.upgrade_idle_timeout = Some(timeout);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn allow(&mut self, cidr: Cidr) -> &mut Self {
+        self.allow.push(cidr);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn deny(&mut self, cidr: Cidr) -> &mut Self {
+        self.deny.push(cidr);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn access_order(&mut self, order: AccessOrder) -> &mut Self {
+        self.access_order = order;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn auto_ban(&mut self, rule: AutoBan) -> &mut Self {
+        self.auto_ban = Some(rule);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn max_connections_per_client(&mut self, max: u32) -> &mut Self {
+        self.max_connections_per_client = Some(max);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn rate_limit_per_client(&mut self, limit: RateLimit) -> &mut Self {
+        self.rate_limit_per_client = Some(limit);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn rate_limit_per_subnet(&mut self, limit: RateLimit) -> &mut Self {
+        self.rate_limit_per_subnet = Some(limit);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn max_tracked_clients(&mut self, max: usize) -> &mut Self {
+        self.max_tracked_clients = max;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn max_sessions(&mut self, max: usize) -> &mut Self {
+        self.max_sessions = Some(max);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn overload_policy(&mut self, policy: OverloadPolicy) -> &mut Self {
+        self.overload_policy = policy;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn max_connections_per_backend(&mut self, max: u32) -> &mut Self {
+        self.max_connections_per_backend = Some(max);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn max_queue_length(&mut self, max: usize) -> &mut Self {
+        self.max_queue_length = max;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn queue_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.queue_timeout = timeout;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn no_backend_grace_period(&mut self, grace_period: Duration) -> &mut Self {
+        self.no_backend_grace_period = Some(grace_period);
+        self
+    }
+    /// Sends a PROXY protocol header to the backends of the default pool.
+    #[allow(dead_code)]
+    pub fn proxy_protocol(&mut self, proxy_protocol: ProxyProtocol) -> &mut Self {
+        self.proxy_protocol.push((None, proxy_protocol));
+        self
+    }
+    /// Sends a PROXY protocol header to the backends of the named pool.
+    #[allow(dead_code)]
+    pub fn pool_proxy_protocol(&mut self, pool: &str, proxy_protocol: ProxyProtocol) -> &mut Self {
+        self.proxy_protocol
+            .push((Some(pool.to_string()), proxy_protocol));
+        self
+    }
+    /// Expects a PROXY protocol header on the connections from these trusted balancers.
+    #[allow(dead_code)]
+    pub fn accept_proxy_protocol(&mut self, source: Cidr) -> &mut Self {
+        self.proxy_protocol_sources.push(source);
+        self
+    }
+    /// Terminates TLS on the listener, with this certificate for these server names. The first
+    /// certificate is the default one, for clients that don't ask for a known server name.
+    #[allow(dead_code)]
+    pub fn tls_certificate<P: Into<PathBuf>>(
+        &mut self,
+        server_names: &[&str],
+        certificate_path: P,
+        key_path: P,
+    ) -> &mut Self {
+        self.tls.certificates.push(TlsCertificate {
+            server_names: server_names.iter().map(|it| it.to_string()).collect(),
+            certificate_path: certificate_path.into(),
+            key_path: key_path.into(),
+        });
+        self
+    }
+    #[allow(dead_code)]
+    pub fn tls_versions(&mut self, versions: &[TlsVersion]) -> &mut Self {
+        self.tls.versions = versions.to_vec();
+        self
+    }
+    #[allow(dead_code)]
+    pub fn tls_cipher_suites(&mut self, cipher_suites: &[&str]) -> &mut Self {
+        self.tls.cipher_suites = cipher_suites.iter().map(|it| it.to_string()).collect();
+        self
+    }
+    /// Sends the clients asking for this server name ("*." matches any single label) to the
+    /// named pool instead of the default one.
+    #[allow(dead_code)]
+    pub fn server_name_route(&mut self, server_name: &str, pool: &str) -> &mut Self {
+        self.server_name_routes
+            .push((server_name.to_string(), pool.to_string()));
+        self
+    }
+    /// Connects to the backends of the default pool with TLS.
+    #[allow(dead_code)]
+    pub fn backend_tls(&mut self, settings: BackendTlsSettings) -> &mut Self {
+        self.backend_tls.push((None, settings));
+        self
+    }
+    /// Connects to the backends of the named pool with TLS.
+    #[allow(dead_code)]
+    pub fn pool_backend_tls(&mut self, pool: &str, settings: BackendTlsSettings) -> &mut Self {
+        self.backend_tls.push((Some(pool.to_string()), settings));
+        self
+    }
+    /// Sends the requests to the backends of the default pool with HTTP/2 (HTTP mode only), as
+    /// needed by gRPC.
+    #[allow(dead_code)]
+    pub fn backend_http2(&mut self) -> &mut Self {
+        self.backend_http2.push(None);
+        self
+    }
+    /// Sends the requests to the backends of the named pool with HTTP/2 (HTTP mode only).
+    #[allow(dead_code)]
+    pub fn pool_backend_http2(&mut self, pool: &str) -> &mut Self {
+        self.backend_http2.push(Some(pool.to_string()));
+        self
+    }
+    #[allow(dead_code)]
+    pub fn no_certificate_check(&mut self) -> &mut Self {
+        self.certificate_check_interval = None;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn certificate_check_interval(&mut self, interval: Duration) -> &mut Self {
+        self.certificate_check_interval = Some(interval);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn protocol(&mut self, protocol: Protocol) -> &mut Self {
+        self.protocol = protocol;
+        self
+    }
+    /// Adds a route, evaluated after the ones already added (HTTP mode only).
+    #[allow(dead_code)]
+    pub fn http_route(&mut self, route: HttpRoute) -> &mut Self {
+        self.http_routes.push(route);
+        self
+    }
+    /// Adds a rule applied to all the requests, evaluated after the ones already added (HTTP mode
+    /// only).
+    #[allow(dead_code)]
+    pub fn header_rule(&mut self, rule: HeaderRule) -> &mut Self {
+        self.header_rules.push(rule);
+        self
+    }
+    /// Adds a redirect or rewrite rule applied to all the requests, evaluated after the ones
+    /// already added (HTTP mode only).
+    #[allow(dead_code)]
+    pub fn url_rule(&mut self, rule: UrlRule) -> &mut Self {
+        self.url_rules.push(rule);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn forwarded_headers(&mut self, forwarded_headers: ForwardedHeaders) -> &mut Self {
+        self.forwarded_headers = forwarded_headers;
+        self
+    }
+    /// Proxies in front of headmaster whose forwarded headers are appended to instead of replaced.
+    #[allow(dead_code)]
+    pub fn trust_forwarded_headers(&mut self, source: Cidr) -> &mut Self {
+        self.forwarded_headers_sources.push(source);
+        self
+    }
+    /// Sends the next requests of a client to the backend that served its first one, with a
+    /// cookie signed with the secret. Clients go to another backend when theirs is removed,
+    /// disabled or full.
+    #[allow(dead_code)]
+    pub fn sticky_cookie(&mut self, name: &str, secret: &[u8]) -> &mut Self {
+        self.sticky_cookie = Some(StickyCookie::new(name, secret));
+        self
+    }
+    /// Retries the failed idempotent requests on other backends in HTTP mode (disabled by
+    /// default).
+    #[allow(dead_code)]
+    pub fn retries(&mut self, settings: RetrySettings) -> &mut Self {
+        self.retries = Some(settings);
+        self
+    }
+    /// Compresses the responses in HTTP mode (disabled by default).
+    #[allow(dead_code)]
+    pub fn compression(&mut self, settings: CompressionSettings) -> &mut Self {
+        self.compression = Some(settings);
+        self
+    }
+    /// Caches the responses in HTTP mode (disabled by default).
+    #[allow(dead_code)]
+    pub fn cache(&mut self, settings: CacheSettings) -> &mut Self {
+        self.cache = Some(settings);
+        self
+    }
+    /// Response sent for the requests that fail this way, in HTTP mode. The status is the one of
+    /// the failure (502, 503 or 504).
+    #[allow(dead_code)]
+    pub fn error_response(
+        &mut self,
+        failure: Failure,
+        settings: ErrorResponseSettings,
+    ) -> &mut Self {
+        self.error_responses.push((failure, settings));
+        self
+    }
+    /// Idle connections kept open to each backend in HTTP mode, 0 to close them after each request.
+    #[allow(dead_code)]
+    pub fn max_idle_connections_per_backend(&mut self, max: usize) -> &mut Self {
+        self.max_idle_connections_per_backend = max;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn idle_connection_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.idle_connection_timeout = Some(timeout);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn no_idle_connection_timeout(&mut self) -> &mut Self {
+        self.idle_connection_timeout = None;
+        self
+    }
+    #[allow(dead_code)]
+    pub fn client_limit_exemption(&mut self, cidr: Cidr) -> &mut Self {
+        self.client_limit_exemptions.push(cidr);
         self
     }
     pub fn build(&self) -> ConfImpl {
         ConfImpl {
             bind_address: self.bind_address.clone(),
             admin_address: self.admin_address.clone(),
+            admin_sources: self.admin_sources.iter().map(|it| (*it, ())).collect(),
             connection_timeout: self.connection_timeout,
             read_timeout: self.read_timeout,
             write_timeout: self.write_timeout,
-            backends: ShardedLock::new(vec![]),
-            blacklist: self.blacklist.iter().map(|it| it.clone()).collect(),
+            upgrade_idle_timeout: self.upgrade_idle_timeout,
+            pool: self.pool(None),
+            pools: self
+                .server_name_routes
+                .iter()
+                .map(|(_, pool)| pool)
+                .chain(self.http_routes.iter().map(|it| &it.pool))
+                .chain(
+                    self.backend_tls
+                        .iter()
+                        .filter_map(|(pool, _)| pool.as_ref()),
+                )
+                .chain(self.backend_http2.iter().flatten())
+                .chain(
+                    self.proxy_protocol
+                        .iter()
+                        .filter_map(|(pool, _)| pool.as_ref()),
+                )
+                .map(|pool| (pool.clone(), self.pool(Some(pool))))
+                .collect(),
+            server_name_routes: {
+                let mut routes = HostMap::new();
+                for (server_name, pool) in &self.server_name_routes {
+                    routes.insert(server_name, pool.clone());
+                }
+                routes
+            },
+            access: AccessList::new(
+                &self.allow,
+                &self.deny,
+                self.access_order,
+                self.auto_ban,
+                self.max_tracked_clients,
+            ),
+            limits: ClientLimits::new(
+                self.max_connections_per_client,
+                self.rate_limit_per_client,
+                self.rate_limit_per_subnet,
+                &self.client_limit_exemptions,
+                self.max_tracked_clients,
+            ),
+            session_limit: SessionLimit::new(self.max_sessions, self.overload_policy),
+            proxy_protocol_sources: self
+                .proxy_protocol_sources
+                .iter()
+                .map(|it| (*it, ()))
+                .collect(),
+            certificate_check_interval: self.certificate_check_interval,
+            protocol: self.protocol,
+            http_routes: self.http_routes.clone(),
+            header_rules: self.header_rules.clone(),
+            url_rules: self.url_rules.clone(),
+            forwarded_headers: self.forwarded_headers,
+            forwarded_headers_sources: self
+                .forwarded_headers_sources
+                .iter()
+                .map(|it| (*it, ()))
+                .collect(),
+            sticky_cookie: self.sticky_cookie.clone(),
+            retries: self.retries.clone().map(Retries::new),
+            compression: self.compression.clone(),
+            cache: self.cache.clone().map(Cache::new),
+            error_responses: self
+                .error_responses
+                .iter()
+                .map(|(failure, settings)| (*failure, ErrorResponse::new(settings.clone())))
+                .collect(),
+            tls: if self.tls.certificates.is_empty() {
+                None
+            } else {
+                let mut settings = self.tls.clone();
+                if self.protocol == Protocol::Http {
+                    settings.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+                }
+                Some(Tls::new(settings))
+            },
         }
     }
 
+    fn pool(&self, name: Option<&str>) -> Pool {
+        let http2 = self.backend_http2.iter().any(|it| it.as_deref() == name);
+        Pool::new(
+            self.max_connections_per_backend,
+            self.max_queue_length,
+            self.queue_timeout,
+            self.no_backend_grace_period,
+            BackendSettings {
+                pool: name.map(|it| it.to_string()),
+                proxy_protocol: self
+                    .proxy_protocol
+                    .iter()
+                    .rev()
+                    .find(|(pool, _)| pool.as_deref() == name)
+                    .map(|(_, proxy_protocol)| proxy_protocol.clone()),
+                tls: self
+                    .backend_tls
+                    .iter()
+                    .rev()
+                    .find(|(pool, _)| pool.as_deref() == name)
+                    .map(|(_, settings)| {
+                        let tls = BackendTls::new(settings.clone());
+                        if http2 {
+                            tls.with_alpn_protocols(vec![b"h2".to_vec()])
+                        } else {
+                            tls
+                        }
+                    }),
+                http2,
+                max_idle_connections: self.max_idle_connections_per_backend,
+                idle_connection_timeout: self.idle_connection_timeout,
+            },
+        )
+    }
+
     fn admin_address_from(bind_address: &BindAddress) -> BindAddress {
         match bind_address {
+            // only reachable from the host itself unless bound explicitly elsewhere
             BindAddress::TcpSocket(address) => BindAddress::TcpSocket(SocketAddr::from((
-                address.ip(),
+                if address.is_ipv6() {
+                    IpAddr::from(Ipv6Addr::LOCALHOST)
+                } else {
+                    IpAddr::from(Ipv4Addr::LOCALHOST)
+                },
                 if address.port() == 8000 { 8001 } else { 8000 },
             ))),
             #[cfg(target_os = "unix")]
             UnixSocket(fd) => BindAddress::TcpSocket {
-                address: SocketAddr::from((0, 0, 0, 0), 8000),
+                address: SocketAddr::from((127, 0, 0, 1), 8000),
             },
         }
     }
@@ -168,16 +745,57 @@ impl ConfBuilder {
 pub struct ConfImpl {
     bind_address: BindAddress,
     admin_address: BindAddress,
+    admin_sources: CidrTrie<()>,
     connection_timeout: Option<Duration>,
     read_timeout: Option<Duration>,
     write_timeout: Option<Duration>,
-    backends: ShardedLock<Vec<Arc<Backend>>>,
-    blacklist: HashSet<SocketAddr>,
+    upgrade_idle_timeout: Option<Duration>,
+    pool: Pool,
+    pools: HashMap<String, Pool>,
+    server_name_routes: HostMap<String>,
+    access: AccessList,
+    limits: ClientLimits,
+    session_limit: SessionLimit,
+    proxy_protocol_sources: CidrTrie<()>,
+    tls: Option<Tls>,
+    certificate_check_interval: Option<Duration>,
+    protocol: Protocol,
+    http_routes: Vec<HttpRoute>,
+    header_rules: Vec<HeaderRule>,
+    url_rules: Vec<UrlRule>,
+    forwarded_headers: ForwardedHeaders,
+    forwarded_headers_sources: CidrTrie<()>,
+    sticky_cookie: Option<StickyCookie>,
+    retries: Option<Retries>,
+    compression: Option<CompressionSettings>,
+    cache: Option<Cache>,
+    error_responses: HashMap<Failure, ErrorResponse>,
 }
 
 impl ConfImpl {
-    fn backend(backends: &Vec<Arc<Backend>>) -> Option<&Arc<Backend>> {
-        backends.first()
+    fn all_pools(&self) -> impl Iterator<Item = &Pool> {
+        std::iter::once(&self.pool).chain(self.pools.values())
+    }
+    // The pool of the HTTP route, else of the server name route, else the default one.
+    fn pool_for(&self, metadata: &Metadata<'_>) -> &Pool {
+        metadata
+            .request
+            .and_then(|request| self.http_routes.iter().find(|it| it.matches(request)))
+            .map(|it| &it.pool)
+            .or_else(|| {
+                metadata
+                    .client_hello
+                    .and_then(|it| it.server_name.as_deref())
+                    .and_then(|it| self.server_name_routes.get(it))
+            })
+            .and_then(|it| self.pools.get(it))
+            .unwrap_or(&self.pool)
+    }
+    fn pool_of(&self, backend: &Arc<Backend>) -> &Pool {
+        backend
+            .pool()
+            .and_then(|it| self.pools.get(it))
+            .unwrap_or(&self.pool)
     }
 }
 
@@ -189,32 +807,143 @@ impl Conf<Arc<Backend>> for ConfImpl {
     fn admin_address(&self) -> &BindAddress {
         &self.admin_address
     }
+    fn accepts_admin(&self, peer_address: &SocketAddr) -> bool {
+        // IPv4 peers of a dual-stack listener are seen as IPv4-mapped IPv6 addresses
+        let ip = normalize(peer_address.ip());
+        ip.is_loopback() || self.admin_sources.contains(&ip)
+    }
+    fn session_limit(&self) -> &SessionLimit {
+        &self.session_limit
+    }
+    fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+    fn tls(&self) -> Option<&Tls> {
+        self.tls.as_ref()
+    }
+    fn load_certificates(&self) -> Result<(), Error> {
+        let acceptor = match self.tls {
+            Some(ref tls) => Some((tls, tls.prepare()?)),
+            None => None,
+        };
+        let mut connectors = vec![];
+        for pool in self.all_pools() {
+            if let Some(ref tls) = pool.settings().tls {
+                connectors.push((tls, tls.prepare()?));
+            }
+        }
+        if let Some((tls, acceptor)) = acceptor {
+            tls.install(acceptor);
+        }
+        for (tls, connector) in connectors {
+            tls.install(connector);
+        }
+        Ok(())
+    }
+    fn load_error_responses(&self) -> Result<(), Error> {
+        for response in self.error_responses.values() {
+            response.load()?;
+        }
+        Ok(())
+    }
+    fn error_response(&self, failure: Failure) -> Option<&ErrorResponse> {
+        self.error_responses.get(&failure)
+    }
+    fn certificate_paths(&self) -> Vec<&Path> {
+        self.tls
+            .iter()
+            .flat_map(|it| it.paths())
+            .chain(
+                self.all_pools()
+                    .filter_map(|it| it.settings().tls.as_ref())
+                    .flat_map(|it| it.paths()),
+            )
+            .collect()
+    }
+    fn certificate_check_interval(&self) -> Option<Duration> {
+        self.certificate_check_interval
+    }
+    fn expects_proxy_protocol(&self, peer_address: &SocketAddr) -> bool {
+        self.proxy_protocol_sources.contains(&peer_address.ip())
+    }
+    fn forwarded_headers(&self) -> ForwardedHeaders {
+        self.forwarded_headers
+    }
+    fn trusts_forwarded_headers(&self, remote_address: &SocketAddr) -> bool {
+        self.forwarded_headers_sources
+            .contains(&remote_address.ip())
+    }
+    fn header_rules(&self, remote_address: &SocketAddr, request: &Parts) -> Vec<&HeaderRule> {
+        let route = self.http_routes.iter().find(|it| it.matches(request));
+        self.header_rules
+            .iter()
+            .chain(route.iter().flat_map(|it| it.header_rules.iter()))
+            .filter(|it| it.matches(remote_address, request))
+            .collect()
+    }
+    fn url_rules(&self, request: &Parts) -> Vec<&UrlRule> {
+        let route = self.http_routes.iter().find(|it| it.matches(request));
+        self.url_rules
+            .iter()
+            .chain(route.iter().flat_map(|it| it.url_rules.iter()))
+            .collect()
+    }
     fn accept(&self, remote_address: &SocketAddr) -> Option<Self::Trace> {
         let start_time = Instant::now();
-        if self.blacklist.contains(remote_address) {
-            None
-        } else {
+        let ip = remote_address.ip();
+        if self.access.permits(&ip, start_time) && self.limits.acquire(&ip, start_time) {
             Some(start_time)
+        } else {
+            None
         }
     }
 
-    fn select(&self, remote_address: &SocketAddr, _trace: Self::Trace) -> Option<Arc<Backend>> {
-        let selected = if let Ok(backends) = self.backends.read() {
-            if let Some(first) = Self::backend(&*backends) {
-                let backend = first.clone();
-                Some(backend)
-            } else {
-                None
-            }
-        } else {
-            None
-        };
-        if let Some(ref backend) = selected {
-            backend.active_counter.fetch_add(1, Ordering::Relaxed);
-        } else {
-            eprintln!("{} => NO BACKEND", remote_address);
+    fn routes_by_server_name(&self) -> bool {
+        !self.server_name_routes.is_empty()
+    }
+
+    async fn select(
+        &self,
+        _remote_address: &SocketAddr,
+        metadata: Metadata<'_>,
+        _trace: Self::Trace,
+    ) -> Result<Arc<Backend>, Unavailable> {
+        let pool = self.pool_for(&metadata);
+        let sticky = self.sticky_cookie.as_ref().and_then(|sticky| {
+            let token = sticky.value(metadata.request?)?;
+            pool.select_matching(|it| sticky.matches(token, &it.address))
+        });
+        match sticky {
+            Some(backend) => Ok(backend),
+            None => pool.select().await,
+        }
+    }
+    fn select_other(
+        &self,
+        metadata: Metadata<'_>,
+        excluded: &[SocketAddr],
+    ) -> Option<Arc<Backend>> {
+        self.pool_for(&metadata)
+            .select_matching(|it| !excluded.contains(&it.address))
+    }
+    fn retries(&self) -> Option<&Retries> {
+        self.retries.as_ref()
+    }
+    fn compression(&self) -> Option<&CompressionSettings> {
+        self.compression.as_ref()
+    }
+    fn cache(&self) -> Option<&Cache> {
+        self.cache.as_ref()
+    }
+    fn sticky_cookie(&self, request: &Parts, backend: &Arc<Backend>) -> Option<String> {
+        let sticky = self.sticky_cookie.as_ref()?;
+        match sticky.value(request) {
+            Some(token) if sticky.matches(token, &backend.address) => None,
+            _ => Some(sticky.set_cookie(&backend.address)),
         }
-        selected
+    }
+    fn release(&self, remote_address: &SocketAddr, _trace: Self::Trace) {
+        self.limits.release(&remote_address.ip(), Instant::now());
     }
     fn connection_timeout(&self) -> Option<Duration> {
         self.connection_timeout
@@ -225,27 +954,49 @@ impl Conf<Arc<Backend>> for ConfImpl {
     fn write_timeout(&self) -> Option<Duration> {
         self.write_timeout
     }
+    fn upgrade_idle_timeout(&self) -> Option<Duration> {
+        self.upgrade_idle_timeout
+    }
     fn add_backend(&self, backend_address: SocketAddr) {
-        let mut backends = self.backends.write().unwrap();
-        if backends
-            .iter()
-            .enumerate()
-            .find(|it| it.1.address == backend_address)
-            .is_none()
-        {
-            backends.push(Arc::new(Backend::init(backend_address)));
+        self.pool.add_backend(backend_address);
+    }
+    fn add_pool_backend(&self, pool: &str, backend_address: SocketAddr) -> bool {
+        match self.pools.get(pool) {
+            Some(pool) => {
+                pool.add_backend(backend_address);
+                true
+            }
+            None => false,
         }
     }
     fn remove_backend(&self, backend_address: SocketAddr) {
-        let mut backends = self.backends.write().unwrap();
-        if let Some((pos, _)) = backends
-            .iter()
-            .enumerate()
-            .find(|it| it.1.address == backend_address)
-        {
-            backends.remove(pos);
+        for pool in self.all_pools() {
+            pool.remove_backend(backend_address);
+        }
+    }
+    fn set_backend_max_connections(
+        &self,
+        backend_address: SocketAddr,
+        max_connections: Option<u32>,
+    ) {
+        for pool in self.all_pools() {
+            pool.set_max_connections(backend_address, max_connections);
         }
     }
+    fn set_backend_available(&self, backend_address: SocketAddr, available: bool) {
+        for pool in self.all_pools() {
+            pool.set_available(backend_address, available);
+        }
+    }
+    fn add_deny(&self, cidr: Cidr, duration: Option<Duration>) {
+        self.access.add_deny(cidr, duration, Instant::now());
+    }
+    fn remove_deny(&self, cidr: Cidr) {
+        self.access.remove_deny(&cidr);
+    }
+    fn denied(&self) -> Vec<(Cidr, Option<Duration>)> {
+        self.access.denied(Instant::now())
+    }
     fn record_success(
         &self,
         remote_address: &SocketAddr,
@@ -255,9 +1006,7 @@ impl Conf<Arc<Backend>> for ConfImpl {
         trace: Self::Trace,
     ) {
         let time = Instant::now().duration_since(trace);
-        backend_address
-            .active_counter
-            .fetch_sub(1, Ordering::Relaxed);
+        self.pool_of(&backend_address).release(&backend_address);
         backend_address.last_failure.store(0, Ordering::Relaxed);
         println!(
             "{} [{}] => {} [{}] ({}ms)",
@@ -268,6 +1017,33 @@ impl Conf<Arc<Backend>> for ConfImpl {
             time.as_millis()
         );
     }
+    fn record_no_backend(&self, remote_address: &SocketAddr, trace: Self::Trace) {
+        let time = Instant::now().duration_since(trace);
+        eprintln!(
+            "{} => {} ({}ms)",
+            remote_address,
+            Unavailable::NoBackend,
+            time.as_millis()
+        );
+    }
+    fn record_queue_full(&self, remote_address: &SocketAddr, trace: Self::Trace) {
+        let time = Instant::now().duration_since(trace);
+        eprintln!(
+            "{} => {} ({}ms)",
+            remote_address,
+            Unavailable::QueueFull,
+            time.as_millis()
+        );
+    }
+    fn record_queue_timeout(&self, remote_address: &SocketAddr, trace: Self::Trace) {
+        let time = Instant::now().duration_since(trace);
+        eprintln!(
+            "{} => {} ({}ms)",
+            remote_address,
+            Unavailable::QueueTimeout,
+            time.as_millis()
+        );
+    }
     fn record_connection_failure(
         &self,
         remote_address: &SocketAddr,
@@ -276,9 +1052,7 @@ impl Conf<Arc<Backend>> for ConfImpl {
         trace: Self::Trace,
     ) {
         let time = Instant::now().duration_since(trace);
-        backend_address
-            .active_counter
-            .fetch_sub(1, Ordering::Relaxed);
+        self.pool_of(&backend_address).release(&backend_address);
         backend_address
             .last_failure
             .store(time.as_secs(), Ordering::Relaxed);
@@ -298,9 +1072,7 @@ impl Conf<Arc<Backend>> for ConfImpl {
         trace: Self::Trace,
     ) {
         let time = Instant::now().duration_since(trace);
-        backend_address
-            .active_counter
-            .fetch_sub(1, Ordering::Relaxed);
+        self.pool_of(&backend_address).release(&backend_address);
         backend_address
             .last_failure
             .store(time.as_secs(), Ordering::Relaxed);
@@ -320,9 +1092,7 @@ impl Conf<Arc<Backend>> for ConfImpl {
         trace: Self::Trace,
     ) {
         let time = Instant::now().duration_since(trace);
-        backend_address
-            .active_counter
-            .fetch_sub(1, Ordering::Relaxed);
+        self.pool_of(&backend_address).release(&backend_address);
         backend_address
             .last_failure
             .store(time.as_secs(), Ordering::Relaxed);
@@ -342,9 +1112,7 @@ impl Conf<Arc<Backend>> for ConfImpl {
         trace: Self::Trace,
     ) {
         let time = Instant::now().duration_since(trace);
-        backend_address
-            .active_counter
-            .fetch_sub(1, Ordering::Relaxed);
+        self.pool_of(&backend_address).release(&backend_address);
         backend_address
             .last_failure
             .store(time.as_secs(), Ordering::Relaxed);
@@ -364,9 +1132,7 @@ impl Conf<Arc<Backend>> for ConfImpl {
         trace: Self::Trace,
     ) {
         let time = Instant::now().duration_since(trace);
-        backend_address
-            .active_counter
-            .fetch_sub(1, Ordering::Relaxed);
+        self.pool_of(&backend_address).release(&backend_address);
         backend_address
             .last_failure
             .store(time.as_secs(), Ordering::Relaxed);
@@ -386,9 +1152,7 @@ impl Conf<Arc<Backend>> for ConfImpl {
         trace: Self::Trace,
     ) {
         let time = Instant::now().duration_since(trace);
-        backend_address
-            .active_counter
-            .fetch_sub(1, Ordering::Relaxed);
+        self.pool_of(&backend_address).release(&backend_address);
         backend_address
             .last_failure
             .store(time.as_secs(), Ordering::Relaxed);
@@ -400,28 +1164,23 @@ impl Conf<Arc<Backend>> for ConfImpl {
             error
         );
     }
-}
-
-pub struct Backend {
-    address: SocketAddr,
-    active_counter: AtomicI32,
-    last_failure: AtomicU64, // secs
-    unavailable: AtomicBool,
-}
-
-impl ToSocketAddr for Arc<Backend> {
-    fn address(&self) -> &SocketAddr {
-        &self.address
+    fn record_cancelled(
+        &self,
+        remote_address: &SocketAddr,
+        backend_address: Arc<Backend>,
+        trace: Self::Trace,
+    ) {
+        let time = Instant::now().duration_since(trace);
+        self.pool_of(&backend_address).release(&backend_address);
+        eprintln!(
+            "{} [] => {} [CANCELLED] ({}ms)",
+            remote_address,
+            backend_address.address,
+            time.as_millis()
+        );
     }
-}
-
-impl Backend {
-    fn init(address: SocketAddr) -> Self {
-        Self {
-            address,
-            active_counter: AtomicI32::new(0),
-            last_failure: AtomicU64::new(0),
-            unavailable: AtomicBool::new(false),
-        }
+    fn record_client_failure(&self, remote_address: &SocketAddr) {
+        self.access
+            .record_failure(&remote_address.ip(), Instant::now());
     }
 }
@@ -1,8 +1,21 @@
 use crate::errors::Error::IOError;
+use std::fmt::{Display, Formatter};
 
 #[derive(Debug)]
 pub enum Error {
     IOError(std::io::Error),
+    InvalidCidr(String),
+    InvalidTls(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IOError(err) => write!(f, "{}", err),
+            Self::InvalidCidr(cidr) => write!(f, "invalid cidr: {}", cidr),
+            Self::InvalidTls(reason) => write!(f, "invalid tls configuration: {}", reason),
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
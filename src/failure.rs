@@ -0,0 +1,87 @@
+use crate::errors::Error;
+use crate::pool::Unavailable;
+use bytes::Bytes;
+use crossbeam::sync::ShardedLock;
+use http::StatusCode;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Why a request couldn't be served, as told to the `record_*` callbacks.
+// the variants are named after the callbacks (record_read_failure...), hence the shared suffixes
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Failure {
+    NoBackend,
+    QueueFull,
+    QueueTimeout,
+    ConnectionFailure,
+    ConnectionTimeout,
+    ReadFailure,
+    ReadTimeout,
+}
+
+impl Failure {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::NoBackend | Self::QueueFull | Self::QueueTimeout => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Self::ConnectionFailure | Self::ReadFailure => StatusCode::BAD_GATEWAY,
+            Self::ConnectionTimeout | Self::ReadTimeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+}
+
+impl From<Unavailable> for Failure {
+    fn from(reason: Unavailable) -> Self {
+        match reason {
+            Unavailable::NoBackend => Self::NoBackend,
+            Unavailable::QueueFull => Self::QueueFull,
+            Unavailable::QueueTimeout => Self::QueueTimeout,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ErrorResponseSettings {
+    pub body_path: PathBuf,
+    pub content_type: String,
+    /// Sent as Retry-After, in seconds.
+    pub retry_after: Option<Duration>,
+}
+
+/// Response sent to HTTP clients when their request fails, instead of an empty one. The body is
+/// read from the file when loading.
+pub struct ErrorResponse {
+    settings: ErrorResponseSettings,
+    body: ShardedLock<Bytes>,
+}
+
+impl ErrorResponse {
+    pub fn new(settings: ErrorResponseSettings) -> Self {
+        Self {
+            settings,
+            body: ShardedLock::new(Bytes::new()),
+        }
+    }
+    pub fn load(&self) -> Result<(), Error> {
+        let path = &self.settings.body_path;
+        let body = std::fs::read(path).map_err(|e| {
+            Error::IOError(std::io::Error::new(
+                e.kind(),
+                format!("{}: {}", path.display(), e),
+            ))
+        })?;
+        *self.body.write().unwrap() = Bytes::from(body);
+        Ok(())
+    }
+    pub fn body(&self) -> Bytes {
+        self.body.read().unwrap().clone()
+    }
+    pub fn content_type(&self) -> &str {
+        &self.settings.content_type
+    }
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.settings.retry_after
+    }
+}
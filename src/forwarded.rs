@@ -0,0 +1,89 @@
+use http::header::{HeaderName, FORWARDED, HOST};
+use http::{HeaderMap, HeaderValue};
+use std::net::{IpAddr, SocketAddr};
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_PORT: HeaderName = HeaderName::from_static("x-forwarded-port");
+
+/// Headers added to the requests in HTTP mode, telling the backends about the client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ForwardedHeaders {
+    /// X-Forwarded-For, X-Forwarded-Proto and X-Forwarded-Port.
+    pub x_forwarded: bool,
+    /// RFC 7239 Forwarded.
+    pub forwarded: bool,
+}
+
+/// The client connection, as seen by headmaster.
+pub struct Forwarding<'a> {
+    pub remote_address: &'a SocketAddr,
+    pub local_address: Option<&'a SocketAddr>,
+    pub tls: bool,
+}
+
+impl ForwardedHeaders {
+    /// Adds the headers for this client. The headers sent by trusted clients (other proxies) are
+    /// appended to, the ones sent by any other client are replaced since they can't be believed.
+    pub fn apply(&self, headers: &mut HeaderMap, forwarding: &Forwarding, trusted: bool) {
+        let ip = crate::cidr::normalize(forwarding.remote_address.ip());
+        let proto = if forwarding.tls { "https" } else { "http" };
+        if self.x_forwarded {
+            if !trusted {
+                for name in [X_FORWARDED_FOR, X_FORWARDED_PROTO, X_FORWARDED_PORT] {
+                    headers.remove(name);
+                }
+            }
+            append(headers, X_FORWARDED_FOR, &ip.to_string());
+            // a proxy in front of headmaster knows better what the client connected to
+            if !headers.contains_key(X_FORWARDED_PROTO) {
+                headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+            }
+            if let Some(local_address) = forwarding.local_address {
+                if !headers.contains_key(X_FORWARDED_PORT) {
+                    headers.insert(X_FORWARDED_PORT, HeaderValue::from(local_address.port()));
+                }
+            }
+        }
+        if self.forwarded {
+            if !trusted {
+                headers.remove(FORWARDED);
+            }
+            let node = match ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => format!("[{}]", ip),
+            };
+            let mut element = format!("for={};proto={}", quote(&node), proto);
+            if let Some(host) = headers.get(HOST).and_then(|it| it.to_str().ok()) {
+                element.push_str(&format!(";host={}", quote(host)));
+            }
+            append(headers, FORWARDED, &element);
+        }
+    }
+}
+
+// Adds a value to a comma separated list, merging the existing fields into one.
+fn append(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    let mut values: Vec<&str> = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|it| it.to_str().ok())
+        .collect();
+    values.push(value);
+    if let Ok(value) = HeaderValue::from_str(&values.join(", ")) {
+        headers.insert(name, value);
+    }
+}
+
+// Forwarded parameter values are quoted unless they are tokens.
+fn quote(value: &str) -> String {
+    let token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|it| it.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&it));
+    if token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
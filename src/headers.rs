@@ -0,0 +1,140 @@
+use crate::cidr::Cidr;
+use crate::route::{host_matches, PathMatch};
+use http::request::Parts;
+use http::{HeaderMap, HeaderName, HeaderValue, Method};
+use regex::Regex;
+use std::net::SocketAddr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderTarget {
+    /// The request sent to the backend.
+    Request,
+    /// The response sent to the client.
+    Response,
+}
+
+#[derive(Clone, Debug)]
+#[allow(dead_code)]
+pub enum HeaderAction {
+    /// Adds a value, keeping the existing ones.
+    Add(HeaderName, HeaderValue),
+    /// Replaces all the values.
+    Set(HeaderName, HeaderValue),
+    Remove(HeaderName),
+    /// Rewrites the values matching the regex, the replacement can refer to its groups ($1...).
+    Replace(HeaderName, Regex, String),
+}
+
+/// Changes a header of the requests in HTTP mode, or of their responses, when the request matches
+/// all the conditions. The conditions are evaluated on the request as sent by the client.
+#[derive(Clone, Debug)]
+pub struct HeaderRule {
+    pub target: HeaderTarget,
+    pub action: HeaderAction,
+    /// Same as the host of `HttpRoute`.
+    pub host: Option<String>,
+    pub path: Option<PathMatch>,
+    pub method: Option<Method>,
+    pub client: Option<Cidr>,
+}
+
+impl HeaderRule {
+    #[allow(dead_code)]
+    pub fn request(action: HeaderAction) -> Self {
+        Self::new(HeaderTarget::Request, action)
+    }
+    #[allow(dead_code)]
+    pub fn response(action: HeaderAction) -> Self {
+        Self::new(HeaderTarget::Response, action)
+    }
+    #[allow(dead_code)]
+    fn new(target: HeaderTarget, action: HeaderAction) -> Self {
+        Self {
+            target,
+            action,
+            host: None,
+            path: None,
+            method: None,
+            client: None,
+        }
+    }
+    #[allow(dead_code)]
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_ascii_lowercase());
+        self
+    }
+    #[allow(dead_code)]
+    pub fn path_prefix(mut self, prefix: &str) -> Self {
+        self.path = Some(PathMatch::Prefix(prefix.to_string()));
+        self
+    }
+    #[allow(dead_code)]
+    pub fn path_regex(mut self, regex: Regex) -> Self {
+        self.path = Some(PathMatch::Regex(regex));
+        self
+    }
+    #[allow(dead_code)]
+    pub fn method(mut self, method: Method) -> Self {
+        self.method = Some(method);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn client(mut self, client: Cidr) -> Self {
+        self.client = Some(client);
+        self
+    }
+    pub fn matches(&self, remote_address: &SocketAddr, request: &Parts) -> bool {
+        self.host
+            .as_ref()
+            .map(|it| host_matches(it, request))
+            .unwrap_or(true)
+            && self
+                .path
+                .as_ref()
+                .map(|it| it.matches(request))
+                .unwrap_or(true)
+            && self
+                .method
+                .as_ref()
+                .map(|it| *it == request.method)
+                .unwrap_or(true)
+            && self
+                .client
+                .map(|it| it.contains(&remote_address.ip()))
+                .unwrap_or(true)
+    }
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        match self.action {
+            HeaderAction::Add(ref name, ref value) => {
+                headers.append(name, value.clone());
+            }
+            HeaderAction::Set(ref name, ref value) => {
+                headers.insert(name, value.clone());
+            }
+            HeaderAction::Remove(ref name) => {
+                headers.remove(name);
+            }
+            HeaderAction::Replace(ref name, ref regex, ref replacement) => {
+                let values: Vec<HeaderValue> = headers
+                    .get_all(name)
+                    .iter()
+                    .map(|value| {
+                        value
+                            .to_str()
+                            .ok()
+                            .filter(|it| regex.is_match(it))
+                            .and_then(|it| {
+                                let replaced = regex.replace_all(it, replacement.as_str());
+                                HeaderValue::from_str(&replaced).ok()
+                            })
+                            .unwrap_or_else(|| value.clone())
+                    })
+                    .collect();
+                headers.remove(name);
+                for value in values {
+                    headers.append(name, value);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,921 @@
+use crate::cache::{Cache, Lookup};
+use crate::compression::compress;
+use crate::conf::{Conf, Metadata, ToSocketAddr};
+use crate::failure::Failure;
+use crate::forwarded::Forwarding;
+use crate::headers::{HeaderRule, HeaderTarget};
+use crate::pool::Unavailable;
+use crate::rewrite::{self, Outcome};
+use crate::sni::ClientHello;
+use crate::tcp::{
+    connect_backend, copy_bidirectional, timeout, AcceptStream, BackendStream, CopyError, Side,
+};
+use bytes::Bytes;
+use http::header::{
+    HeaderName, CONNECTION, CONTENT_TYPE, HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH, LOCATION,
+    RETRY_AFTER, SET_COOKIE, TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+};
+use http::request::Parts;
+use http::{HeaderMap, HeaderValue, Request, Response, StatusCode, Uri, Version};
+use http_body::{Body, Frame, SizeHint};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::Incoming;
+use hyper::client::conn::{http1, http2};
+use hyper::service::service_fn;
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+pub type ProxyBody = BoxBody<Bytes, BoxError>;
+
+// headers that only apply to a single connection
+const HOP_BY_HOP: [HeaderName; 7] = [
+    CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+/// HTTP/1.1 or HTTP/2 client connection, HTTP/2 being recognized by its connection preface (h2
+/// negotiated with ALPN, or h2c with prior knowledge). Each request, or stream, is routed on its
+/// own, so that the requests of keep-alive and multiplexing clients are balanced over the
+/// backends.
+pub(crate) async fn session<B: ToSocketAddr + Sync + Send + Unpin + 'static, C: Conf<B> + Sync>(
+    config: &'static C,
+    client_stream: AcceptStream,
+    remote_address: &SocketAddr,
+    local_address: Option<&SocketAddr>,
+    client_hello: Option<&ClientHello>,
+    trace: C::Trace,
+) {
+    let session = Arc::new(Session {
+        config,
+        tls: matches!(client_stream, AcceptStream::Tls(_)),
+        remote_address: *remote_address,
+        local_address: local_address.copied(),
+        client_hello: client_hello.cloned(),
+        trace,
+        upgrades: Mutex::new(vec![]),
+    });
+    // HTTP/2 streams are served by tasks of their own
+    let service = {
+        let session = session.clone();
+        service_fn(move |request| {
+            let session = session.clone();
+            async move { session.forward(request).await }
+        })
+    };
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(config.read_timeout());
+    builder.http2().timer(TokioTimer::new());
+    if let Err(e) = builder
+        .serve_connection_with_upgrades(TokioIo::new(client_stream), service)
+        .await
+    {
+        eprintln!("{} => HTTP FAILURE\n{}", remote_address, e);
+        config.record_client_failure(remote_address);
+    }
+    // the session lasts as long as the upgraded connections
+    let upgrades = std::mem::take(&mut *session.upgrades.lock().unwrap());
+    for upgrade in upgrades {
+        let _ = upgrade.await;
+    }
+}
+
+struct Session<B: ToSocketAddr, C: Conf<B> + 'static> {
+    config: &'static C,
+    // whether TLS was terminated
+    tls: bool,
+    remote_address: SocketAddr,
+    local_address: Option<SocketAddr>,
+    client_hello: Option<ClientHello>,
+    trace: C::Trace,
+    // copies of the upgraded connections
+    upgrades: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl<B: ToSocketAddr + Sync + Send + Unpin + 'static, C: Conf<B> + Sync> Session<B, C> {
+    async fn forward(
+        &self,
+        request: Request<Incoming>,
+    ) -> Result<Response<ProxyBody>, hyper::Error> {
+        let (parts, body) = request.into_parts();
+        let rules = self.config.header_rules(&self.remote_address, &parts);
+        let mut response = self.proxy(Request::from_parts(parts, body), &rules).await?;
+        for rule in rules
+            .iter()
+            .filter(|it| it.target == HeaderTarget::Response)
+        {
+            rule.apply(response.headers_mut());
+        }
+        Ok(response)
+    }
+    async fn proxy(
+        &self,
+        mut request: Request<Incoming>,
+        rules: &[&HeaderRule],
+    ) -> Result<Response<ProxyBody>, hyper::Error> {
+        let upgrade = upgrade_headers(request.headers());
+        let mut client_upgrade = if upgrade.is_empty() {
+            None
+        } else {
+            Some(hyper::upgrade::on(&mut request))
+        };
+        let (mut parts, body) = request.into_parts();
+        let version = parts.version;
+        remove_hop_by_hop(&mut parts.headers);
+        parts.headers.extend(upgrade);
+        let url_rules = self.config.url_rules(&parts);
+        if let Outcome::Redirect(status, location) =
+            rewrite::apply(&url_rules, &mut parts, self.tls)
+        {
+            return Ok(redirect(status, &location));
+        }
+        let config = self.config;
+        let remote_address = &self.remote_address;
+        let trace = self.trace;
+        let cached = config
+            .cache()
+            .and_then(|cache| Some((cache, Cache::key(&parts, self.tls)?)));
+        // the stale response being revalidated with the conditional headers added to the request
+        let mut stale = None;
+        if let Some((cache, ref key)) = cached {
+            match cache.lookup(key, &parts) {
+                Lookup::Fresh(stored) => {
+                    return Ok(self.encoded(&parts, stored.response(&parts), version));
+                }
+                Lookup::Stale(stored) => {
+                    let conditional = parts.headers.contains_key(IF_NONE_MATCH)
+                        || parts.headers.contains_key(IF_MODIFIED_SINCE);
+                    if !conditional {
+                        parts.headers.extend(stored.validators());
+                        stale = Some(stored);
+                    }
+                }
+                Lookup::Miss => {}
+            }
+        }
+        // requests that may be sent again are read before a backend is selected
+        let retries = config
+            .retries()
+            .filter(|it| client_upgrade.is_none() && it.applies(&parts, body.size_hint().exact()));
+        if let Some(retries) = config.retries() {
+            retries.deposit();
+        }
+        let (mut body, replay) = match retries {
+            Some(_) => (None, body.collect().await?.to_bytes()),
+            None => (Some(body.map_err(BoxError::from).boxed()), Bytes::new()),
+        };
+        let metadata = Metadata {
+            client_hello: self.client_hello.as_ref(),
+            request: Some(&parts),
+        };
+        let mut backend = match config.select(remote_address, metadata, trace).await {
+            Ok(backend) => self.reserved(backend),
+            Err(reason) => {
+                match reason {
+                    Unavailable::NoBackend => config.record_no_backend(remote_address, trace),
+                    Unavailable::QueueFull => config.record_queue_full(remote_address, trace),
+                    Unavailable::QueueTimeout => config.record_queue_timeout(remote_address, trace),
+                }
+                return Ok(self.failed(reason.into()));
+            }
+        };
+        let mut tried = vec![];
+        loop {
+            tried.push(*backend.address());
+            let body = body.take().unwrap_or_else(|| full(replay.clone()));
+            let attempt = self
+                .send(&parts, body, backend, client_upgrade.take(), rules)
+                .await;
+            // the backend to send the request to again, if it failed and can be retried
+            let retry = async || {
+                let retries = retries?;
+                if tried.len() > retries.settings().max_retries || !retries.withdraw() {
+                    return None;
+                }
+                let other = config.select_other(metadata, &tried);
+                if other.is_none() {
+                    retries.refund();
+                }
+                other
+            };
+            match attempt {
+                Attempt::Done(response) => return response,
+                Attempt::Answered(response, answered, sender, request_size) => {
+                    let rejected = retries
+                        .map(|it| it.settings().statuses.contains(&response.status()))
+                        .unwrap_or(false);
+                    if rejected {
+                        if let Some(other) = retry().await {
+                            let e = std::io::Error::other(format!(
+                                "rejected status {}",
+                                response.status()
+                            ));
+                            config.record_read_failure(remote_address, answered.take(), e, trace);
+                            backend = self.reserved(other);
+                            continue;
+                        }
+                    }
+                    if let (Some(stored), Some((cache, ref key))) = (&stale, &cached) {
+                        if response.status() == StatusCode::NOT_MODIFIED {
+                            let request_size = request_size.load(Ordering::Relaxed);
+                            config.record_success(
+                                remote_address,
+                                answered.take(),
+                                request_size,
+                                0,
+                                trace,
+                            );
+                            parts.headers.remove(IF_NONE_MATCH);
+                            parts.headers.remove(IF_MODIFIED_SINCE);
+                            let (response, _) = response.into_parts();
+                            let stored = cache.refresh(key, &parts, stored, &response);
+                            return Ok(self.encoded(&parts, stored.response(&parts), version));
+                        }
+                    }
+                    return Ok(self.respond(
+                        &parts,
+                        response,
+                        answered.take(),
+                        sender,
+                        request_size,
+                        version,
+                    ));
+                }
+                // timed out requests may still be processed by the backend, and take as long
+                // on the next one
+                Attempt::Failed(failure, reset) => {
+                    if failure != Failure::ReadTimeout {
+                        if let Some(other) = retry().await {
+                            backend = self.reserved(other);
+                            continue;
+                        }
+                    }
+                    return match reset {
+                        Some(e) => Err(e),
+                        None => Ok(self.failed(failure)),
+                    };
+                }
+            }
+        }
+    }
+    // Guards the backend reserved for the request until its outcome is recorded.
+    fn reserved(&self, backend: B) -> Reserved<B, C> {
+        Reserved {
+            config: self.config,
+            remote_address: self.remote_address,
+            backend: Some(backend),
+            trace: self.trace,
+        }
+    }
+    // Sends the request to the backend, once.
+    async fn send(
+        &self,
+        request: &Parts,
+        body: ProxyBody,
+        backend: Reserved<B, C>,
+        client_upgrade: Option<OnUpgrade>,
+        rules: &[&HeaderRule],
+    ) -> Attempt<Reserved<B, C>> {
+        let config = self.config;
+        let remote_address = &self.remote_address;
+        let trace = self.trace;
+        let mut sender = match self.sender(&backend).await {
+            Ok(sender) => sender,
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                config.record_connection_timeout(remote_address, backend.take(), e, trace);
+                return Attempt::Failed(Failure::ConnectionTimeout, None);
+            }
+            Err(e) => {
+                config.record_connection_failure(remote_address, backend.take(), e, trace);
+                return Attempt::Failed(Failure::ConnectionFailure, None);
+            }
+        };
+        let version = request.version;
+        let (mut parts, ()) = Request::new(()).into_parts();
+        parts.method = request.method.clone();
+        parts.uri = request.uri.clone();
+        parts.headers = request.headers.clone();
+        origin_form(&mut parts);
+        let forwarding = Forwarding {
+            remote_address,
+            local_address: self.local_address.as_ref(),
+            tls: self.tls,
+        };
+        config.forwarded_headers().apply(
+            &mut parts.headers,
+            &forwarding,
+            config.trusts_forwarded_headers(remote_address),
+        );
+        for rule in rules.iter().filter(|it| it.target == HeaderTarget::Request) {
+            rule.apply(&mut parts.headers);
+        }
+        if backend.http2() {
+            absolute_form(&mut parts, backend.tls().is_some(), backend.address());
+            parts.version = Version::HTTP_2;
+        } else {
+            parts.version = Version::HTTP_11;
+        }
+        let request_size = Arc::new(AtomicU64::new(0));
+        let request = Request::from_parts(
+            parts,
+            Counted {
+                body,
+                size: request_size.clone(),
+            }
+            .boxed(),
+        );
+        let response = timeout(config.read_timeout(), async {
+            Ok(sender.send_request(request).await)
+        })
+        .await;
+        match response {
+            Ok(Ok(mut response)) if response.status() == StatusCode::SWITCHING_PROTOCOLS => {
+                let upgrade = upgrade_headers(response.headers());
+                let backend_upgrade = hyper::upgrade::on(&mut response);
+                let (mut parts, _) = response.into_parts();
+                remove_hop_by_hop(&mut parts.headers);
+                parts.headers.extend(upgrade);
+                match client_upgrade {
+                    Some(client_upgrade) => {
+                        let upgraded = upgraded(
+                            config,
+                            *remote_address,
+                            backend.take(),
+                            client_upgrade,
+                            backend_upgrade,
+                            request_size.load(Ordering::Relaxed),
+                            trace,
+                        );
+                        self.upgrades.lock().unwrap().push(tokio::spawn(upgraded));
+                        Attempt::Done(Ok(Response::from_parts(parts, empty())))
+                    }
+                    None => {
+                        let e = std::io::Error::new(ErrorKind::InvalidData, "unexpected upgrade");
+                        config.record_read_failure(remote_address, backend.take(), e, trace);
+                        Attempt::Failed(Failure::ReadFailure, None)
+                    }
+                }
+            }
+            Ok(Ok(response)) => Attempt::Answered(response, backend, sender, request_size),
+            // the stream reset by the backend is reset for the client as well, with the same
+            // reason (hyper looks for it in the error sources)
+            Ok(Err(e)) if version == Version::HTTP_2 && backend.http2() => {
+                let reset = std::io::Error::other(e.to_string());
+                config.record_read_failure(remote_address, backend.take(), reset, trace);
+                Attempt::Failed(Failure::ReadFailure, Some(e))
+            }
+            Ok(Err(e)) => {
+                let e = std::io::Error::other(e);
+                config.record_read_failure(remote_address, backend.take(), e, trace);
+                Attempt::Failed(Failure::ReadFailure, None)
+            }
+            Err(e) => {
+                config.record_read_timeout(remote_address, backend.take(), e, trace);
+                Attempt::Failed(Failure::ReadTimeout, None)
+            }
+        }
+    }
+    // The response of the backend for the client, recording the outcome once it is sent.
+    fn respond(
+        &self,
+        request: &Parts,
+        response: Response<Incoming>,
+        backend: B,
+        sender: Sender,
+        request_size: Arc<AtomicU64>,
+        version: Version,
+    ) -> Response<ProxyBody> {
+        let (mut parts, body) = response.into_parts();
+        remove_hop_by_hop(&mut parts.headers);
+        let set_cookie = self.config.sticky_cookie(request, &backend);
+        let body = Recorded {
+            body,
+            config: self.config,
+            remote_address: self.remote_address,
+            backend: Some(backend),
+            sender: match sender {
+                Sender::Http1(sender) => Some(sender),
+                Sender::Http2(_) => None,
+            },
+            request_size,
+            response_size: 0,
+            trace: self.trace,
+        };
+        let mut body = body.boxed();
+        // stored without the cookie, which is only for this client
+        if let Some(cache) = self.config.cache() {
+            if let Some(key) = Cache::key(request, self.tls) {
+                body = cache.store(key, request, &parts, body);
+            }
+        }
+        if let Some(set_cookie) = set_cookie.and_then(|it| HeaderValue::from_str(&it).ok()) {
+            parts.headers.append(SET_COOKIE, set_cookie);
+        }
+        self.encoded(request, Response::from_parts(parts, body), version)
+    }
+    // Compresses the response for the client, if it should be.
+    fn encoded(
+        &self,
+        request: &Parts,
+        response: Response<ProxyBody>,
+        version: Version,
+    ) -> Response<ProxyBody> {
+        let (mut parts, mut body) = response.into_parts();
+        parts.version = version;
+        let compression = self.config.compression();
+        if let Some(encoding) = compression.and_then(|it| it.encoding(request, &parts)) {
+            body = compress(&mut parts, body, encoding);
+        }
+        Response::from_parts(parts, body)
+    }
+    // The configured error response, or an empty one with the status of the failure.
+    fn failed(&self, failure: Failure) -> Response<ProxyBody> {
+        let Some(error_response) = self.config.error_response(failure) else {
+            return status(failure.status());
+        };
+        let mut response = Response::new(full(error_response.body()));
+        *response.status_mut() = failure.status();
+        if let Ok(content_type) = HeaderValue::from_str(error_response.content_type()) {
+            response.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        if let Some(retry_after) = error_response.retry_after() {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after.as_secs()));
+        }
+        response
+    }
+    // Reuses an idle connection to the backend if there is one, or opens a new one.
+    async fn sender(&self, backend: &B) -> Result<Sender, std::io::Error> {
+        if backend.http2() {
+            return self.http2_sender(backend).await.map(Sender::Http2);
+        }
+        if let Some(idle_connections) = reusable(backend) {
+            while let Some(mut sender) = idle_connections.take() {
+                if sender.ready().await.is_ok() {
+                    return Ok(Sender::Http1(sender));
+                }
+            }
+        }
+        let stream = self.connect(backend).await?;
+        let (sender, connection) = http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(std::io::Error::other)?;
+        tokio::spawn(connection.with_upgrades());
+        Ok(Sender::Http1(sender))
+    }
+    // HTTP/2 connections are shared by the concurrent requests, unless they carry a PROXY
+    // protocol header.
+    async fn http2_sender(
+        &self,
+        backend: &B,
+    ) -> Result<http2::SendRequest<ProxyBody>, std::io::Error> {
+        if let Some(mut sender) = reusable(backend).and_then(|it| it.shared()) {
+            if sender.ready().await.is_ok() {
+                return Ok(sender);
+            }
+        }
+        let stream = self.connect(backend).await?;
+        let (sender, connection) = http2::Builder::new(TokioExecutor::new())
+            .timer(TokioTimer::new())
+            .handshake(TokioIo::new(stream))
+            .await
+            .map_err(std::io::Error::other)?;
+        tokio::spawn(connection);
+        if let Some(idle_connections) = reusable(backend) {
+            idle_connections.share(sender.clone());
+        }
+        Ok(sender)
+    }
+    async fn connect(&self, backend: &B) -> Result<BackendStream, std::io::Error> {
+        timeout(
+            self.config.connection_timeout(),
+            connect_backend(
+                backend,
+                &self.remote_address,
+                self.local_address.as_ref(),
+                &[],
+            ),
+        )
+        .await
+    }
+}
+
+enum Attempt<B> {
+    /// The response for the client, or the error resetting its stream.
+    Done(Result<Response<ProxyBody>, hyper::Error>),
+    /// The backend answered, the outcome isn't recorded yet.
+    Answered(Response<Incoming>, B, Sender, Arc<AtomicU64>),
+    /// The backend failed (recorded already), with the error resetting the stream of HTTP/2
+    /// clients when its stream was reset.
+    Failed(Failure, Option<hyper::Error>),
+}
+
+enum Sender {
+    Http1(http1::SendRequest<ProxyBody>),
+    Http2(http2::SendRequest<ProxyBody>),
+}
+
+impl Sender {
+    async fn send_request(
+        &mut self,
+        request: Request<ProxyBody>,
+    ) -> Result<Response<Incoming>, hyper::Error> {
+        match self {
+            Self::Http1(sender) => sender.send_request(request).await,
+            Self::Http2(sender) => sender.send_request(request).await,
+        }
+    }
+}
+
+// Copies the upgraded connection as it is, like in TCP mode, once the backend switched protocols.
+async fn upgraded<B: ToSocketAddr, C: Conf<B>>(
+    config: &'static C,
+    remote_address: SocketAddr,
+    backend: B,
+    client_upgrade: OnUpgrade,
+    backend_upgrade: OnUpgrade,
+    request_size: u64,
+    trace: C::Trace,
+) {
+    let (client_stream, backend_stream) = match tokio::try_join!(client_upgrade, backend_upgrade) {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            let e = std::io::Error::other(e);
+            config.record_write_failure(&remote_address, backend, e, trace);
+            return;
+        }
+    };
+    let copied = copy_bidirectional(
+        TokioIo::new(client_stream),
+        TokioIo::new(backend_stream),
+        config.upgrade_idle_timeout(),
+    )
+    .await;
+    match copied {
+        Ok((upgraded_request_size, response_size)) => config.record_success(
+            &remote_address,
+            backend,
+            request_size + upgraded_request_size,
+            response_size,
+            trace,
+        ),
+        Err(CopyError::ReadFailure(e, _)) if e.kind() == ErrorKind::TimedOut => {
+            config.record_read_timeout(&remote_address, backend, e, trace)
+        }
+        Err(CopyError::ReadFailure(e, side)) => {
+            if side == Side::Client {
+                config.record_client_failure(&remote_address);
+            }
+            config.record_read_failure(&remote_address, backend, e, trace)
+        }
+        Err(CopyError::WriteFailure(e, _)) if e.kind() == ErrorKind::TimedOut => {
+            config.record_write_timeout(&remote_address, backend, e, trace)
+        }
+        Err(CopyError::WriteFailure(e, side)) => {
+            if side == Side::Client {
+                config.record_client_failure(&remote_address);
+            }
+            config.record_write_failure(&remote_address, backend, e, trace)
+        }
+    }
+}
+
+/// Connections to a backend that are kept open between requests, shared by all the clients.
+pub struct IdleConnections {
+    // the most recently used last
+    connections: Mutex<Vec<(http1::SendRequest<ProxyBody>, Instant)>>,
+    // HTTP/2 connection, used by several requests at once
+    shared: Mutex<Option<http2::SendRequest<ProxyBody>>>,
+    max: usize,
+    timeout: Option<Duration>,
+}
+
+impl IdleConnections {
+    pub fn new(max: usize, timeout: Option<Duration>) -> Self {
+        Self {
+            connections: Mutex::new(vec![]),
+            shared: Mutex::new(None),
+            max,
+            timeout,
+        }
+    }
+    fn take(&self) -> Option<http1::SendRequest<ProxyBody>> {
+        let mut connections = self.connections.lock().unwrap();
+        while let Some((sender, since)) = connections.pop() {
+            if self.timeout.is_some_and(|it| since.elapsed() >= it) {
+                // the other ones have been idle for even longer
+                connections.clear();
+                return None;
+            }
+            if !sender.is_closed() {
+                return Some(sender);
+            }
+        }
+        None
+    }
+    fn put(&self, sender: http1::SendRequest<ProxyBody>) {
+        if self.max == 0 || sender.is_closed() {
+            return;
+        }
+        let mut connections = self.connections.lock().unwrap();
+        if connections.len() >= self.max {
+            connections.remove(0);
+        }
+        connections.push((sender, Instant::now()));
+    }
+    fn shared(&self) -> Option<http2::SendRequest<ProxyBody>> {
+        let shared = self.shared.lock().unwrap();
+        shared.as_ref().filter(|it| !it.is_closed()).cloned()
+    }
+    fn share(&self, sender: http2::SendRequest<ProxyBody>) {
+        if self.max > 0 {
+            *self.shared.lock().unwrap() = Some(sender);
+        }
+    }
+}
+
+// Connections to the backend that other clients can reuse, none when they start with a PROXY
+// protocol header naming the client that opened them.
+fn reusable<B: ToSocketAddr>(backend: &B) -> Option<&IdleConnections> {
+    backend
+        .idle_connections()
+        .filter(|_| backend.proxy_protocol().is_none())
+}
+
+fn empty() -> ProxyBody {
+    Empty::new().map_err(|e| match e {}).boxed()
+}
+
+fn full(body: Bytes) -> ProxyBody {
+    Full::new(body).map_err(|e| match e {}).boxed()
+}
+
+fn status(status: StatusCode) -> Response<ProxyBody> {
+    let mut response = Response::new(empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn redirect(status: StatusCode, location: &str) -> Response<ProxyBody> {
+    let mut response = self::status(status);
+    if let Ok(location) = HeaderValue::from_str(location) {
+        response.headers_mut().insert(LOCATION, location);
+    }
+    response
+}
+
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    // "TE: trailers" is allowed with HTTP/2 as well, and gRPC requires it
+    let trailers = headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|it| it.to_str().ok())
+        .flat_map(|it| it.split(','))
+        .any(|it| it.trim().eq_ignore_ascii_case("trailers"));
+    for name in connection_options(headers).iter().chain(HOP_BY_HOP.iter()) {
+        headers.remove(name);
+    }
+    if trailers {
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+    }
+}
+
+// headers listed in Connection
+fn connection_options(headers: &HeaderMap) -> Vec<HeaderName> {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|it| it.to_str().ok())
+        .flat_map(|it| it.split(','))
+        .filter_map(|it| HeaderName::from_bytes(it.trim().as_bytes()).ok())
+        .collect()
+}
+
+// Connection, Upgrade and the other headers listed in Connection (HTTP2-Settings for h2c...),
+// which are kept when switching protocols.
+fn upgrade_headers(headers: &HeaderMap) -> Vec<(HeaderName, HeaderValue)> {
+    let options = connection_options(headers);
+    if !options.contains(&UPGRADE) || !headers.contains_key(UPGRADE) {
+        return vec![];
+    }
+    options
+        .iter()
+        .chain([CONNECTION].iter())
+        .flat_map(|name| {
+            headers
+                .get_all(name)
+                .iter()
+                .map(move |value| (name.clone(), value.clone()))
+        })
+        .collect()
+}
+
+// Backends expect the path only, the host of an absolute URI moves to the Host header.
+fn origin_form(parts: &mut Parts) {
+    if let Some(authority) = parts.uri.authority() {
+        if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+            parts.headers.insert(HOST, host);
+        }
+    }
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map(|it| it.as_str())
+        .unwrap_or("/");
+    if let Ok(uri) = path_and_query.parse::<Uri>() {
+        parts.uri = uri;
+    }
+}
+
+// HTTP/2 requests carry the scheme and the host in the URI, as the :scheme and :authority
+// pseudo-headers, instead of the Host header.
+fn absolute_form(parts: &mut Parts, tls: bool, backend_address: &SocketAddr) {
+    let authority = match parts.headers.remove(HOST) {
+        Some(host) => host.to_str().unwrap_or_default().to_string(),
+        None => backend_address.to_string(),
+    };
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map(|it| it.as_str())
+        .unwrap_or("/");
+    let uri = Uri::builder()
+        .scheme(if tls { "https" } else { "http" })
+        .authority(authority)
+        .path_and_query(path_and_query)
+        .build();
+    if let Ok(uri) = uri {
+        parts.uri = uri;
+    }
+}
+
+// Request body, counting its size.
+struct Counted {
+    body: ProxyBody,
+    size: Arc<AtomicU64>,
+}
+
+impl Body for Counted {
+    type Data = Bytes;
+    type Error = BoxError;
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let frame = ready!(Pin::new(&mut self.body).poll_frame(cx));
+        if let Some(Ok(ref frame)) = frame {
+            if let Some(data) = frame.data_ref() {
+                self.size.fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+        }
+        Poll::Ready(frame)
+    }
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+// Backend reserved for a request, given back if the request is dropped before the outcome is
+// recorded (a stream reset or a client gone before the response).
+struct Reserved<B: ToSocketAddr, C: Conf<B> + 'static> {
+    config: &'static C,
+    remote_address: SocketAddr,
+    // taken once the outcome is recorded
+    backend: Option<B>,
+    trace: C::Trace,
+}
+
+impl<B: ToSocketAddr, C: Conf<B>> Reserved<B, C> {
+    // The backend, for the outcome to be recorded.
+    fn take(mut self) -> B {
+        self.backend.take().unwrap()
+    }
+}
+
+impl<B: ToSocketAddr, C: Conf<B>> Deref for Reserved<B, C> {
+    type Target = B;
+    fn deref(&self) -> &B {
+        self.backend.as_ref().unwrap()
+    }
+}
+
+impl<B: ToSocketAddr, C: Conf<B>> Drop for Reserved<B, C> {
+    fn drop(&mut self) {
+        if let Some(backend) = self.backend.take() {
+            self.config
+                .record_cancelled(&self.remote_address, backend, self.trace);
+        }
+    }
+}
+
+// Response body, recording the outcome of the request once it is sent or interrupted.
+struct Recorded<B: ToSocketAddr, C: Conf<B> + 'static> {
+    body: Incoming,
+    config: &'static C,
+    remote_address: SocketAddr,
+    // taken once the outcome is recorded
+    backend: Option<B>,
+    // connection to the backend, reusable once the response is complete
+    sender: Option<http1::SendRequest<ProxyBody>>,
+    request_size: Arc<AtomicU64>,
+    response_size: u64,
+    trace: C::Trace,
+}
+
+impl<B: ToSocketAddr, C: Conf<B>> Recorded<B, C> {
+    fn record_success(&mut self) {
+        if let Some(backend) = self.backend.take() {
+            if let (Some(sender), Some(idle_connections)) = (self.sender.take(), reusable(&backend))
+            {
+                idle_connections.put(sender);
+            }
+            self.config.record_success(
+                &self.remote_address,
+                backend,
+                self.request_size.load(Ordering::Relaxed),
+                self.response_size,
+                self.trace,
+            );
+        }
+    }
+}
+
+impl<B: ToSocketAddr + Unpin, C: Conf<B>> Body for Recorded<B, C> {
+    type Data = Bytes;
+    type Error = BoxError;
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let frame = ready!(Pin::new(&mut self.body).poll_frame(cx));
+        match frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    self.response_size += data.len() as u64;
+                }
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Some(Err(e)) => {
+                if let Some(backend) = self.backend.take() {
+                    self.config.record_read_failure(
+                        &self.remote_address,
+                        backend,
+                        std::io::Error::other(e.to_string()),
+                        self.trace,
+                    );
+                }
+                Poll::Ready(Some(Err(e.into())))
+            }
+            None => {
+                self.record_success();
+                Poll::Ready(None)
+            }
+        }
+    }
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl<B: ToSocketAddr, C: Conf<B>> Drop for Recorded<B, C> {
+    fn drop(&mut self) {
+        // empty bodies are not necessarily polled
+        if self.body.is_end_stream() {
+            self.record_success();
+        }
+        if let Some(backend) = self.backend.take() {
+            self.config.record_write_failure(
+                &self.remote_address,
+                backend,
+                std::io::Error::new(ErrorKind::ConnectionAborted, "response interrupted"),
+                self.trace,
+            );
+        }
+    }
+}
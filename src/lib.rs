@@ -1,4 +1,25 @@
+mod access;
+pub mod admin;
+pub mod cache;
+pub mod cidr;
+mod clients;
+pub mod compression;
 mod conf;
 pub mod errors;
+pub mod failure;
+pub mod forwarded;
+pub mod headers;
+pub mod http;
+mod limits;
+pub mod pool;
+pub mod proxy;
+pub mod retry;
+pub mod rewrite;
+pub mod route;
+pub mod sni;
+pub mod sticky;
 pub mod tcp;
-pub use conf::{BindAddress, Conf, ConfBuilder, ConfImpl};
+pub mod tls;
+pub use access::{AccessOrder, AutoBan};
+pub use conf::{BindAddress, Conf, ConfBuilder, ConfImpl, Metadata, Protocol, ToSocketAddr};
+pub use limits::{OverloadPolicy, RateLimit, SessionLimit};
@@ -0,0 +1,214 @@
+use crate::cidr::{normalize, Cidr, CidrTrie};
+use crate::clients::{ClientTable, Idle};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Token bucket: allows bursts of up to `burst` connections, refilled at `rate` connections per
+/// second. A rate or a burst of 0 means unlimited.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    fn is_unlimited(&self) -> bool {
+        self.rate.is_nan() || self.rate <= 0.0 || self.burst == 0
+    }
+}
+
+pub struct ClientLimits {
+    max_sessions: Option<u32>,
+    per_client_rate: Option<RateLimit>,
+    per_subnet_rate: Option<RateLimit>,
+    exemptions: CidrTrie<()>,
+    sessions: ClientTable<ClientSessions>,
+    client_buckets: ClientTable<Bucket>,
+    // keyed by the /24 (IPv4) or /64 (IPv6) network address
+    subnet_buckets: ClientTable<Bucket>,
+}
+
+#[derive(Default)]
+struct ClientSessions {
+    active: u32,
+}
+
+impl Idle for ClientSessions {
+    fn is_idle(&self, _now: Instant) -> bool {
+        self.active == 0
+    }
+    fn is_evictable(&self) -> bool {
+        // dropping active sessions would allow the client to go over the limit
+        self.active == 0
+    }
+}
+
+#[derive(Default)]
+struct Bucket {
+    tokens: f64,
+    updated: Option<Instant>,
+    full: Option<Instant>,
+}
+
+impl Idle for Bucket {
+    fn is_idle(&self, now: Instant) -> bool {
+        self.updated.is_none() || self.full.map(|it| now >= it).unwrap_or(false)
+    }
+}
+
+impl Bucket {
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        let burst = limit.burst as f64;
+        let tokens = match self.updated {
+            Some(updated) => {
+                let elapsed = now.duration_since(updated).as_secs_f64();
+                f64::min(burst, self.tokens + elapsed * limit.rate)
+            }
+            None => burst,
+        };
+        let taken = tokens >= 1.0;
+        self.tokens = if taken { tokens - 1.0 } else { tokens };
+        self.updated = Some(now);
+        self.set_full(limit, now);
+        taken
+    }
+    // gives back a token taken for a connection that another limit rejected
+    fn refund(&mut self, limit: &RateLimit, now: Instant) {
+        self.tokens = f64::min(limit.burst as f64, self.tokens + 1.0);
+        self.set_full(limit, now);
+    }
+    // when the bucket is full again, and can be forgotten
+    fn set_full(&mut self, limit: &RateLimit, now: Instant) {
+        self.full = Duration::try_from_secs_f64((limit.burst as f64 - self.tokens) / limit.rate)
+            .ok()
+            .and_then(|it| now.checked_add(it));
+    }
+}
+
+impl ClientLimits {
+    pub fn new(
+        max_sessions: Option<u32>,
+        per_client_rate: Option<RateLimit>,
+        per_subnet_rate: Option<RateLimit>,
+        exemptions: &[Cidr],
+        max_tracked_clients: usize,
+    ) -> Self {
+        Self {
+            max_sessions,
+            per_client_rate: per_client_rate.filter(|it| !it.is_unlimited()),
+            per_subnet_rate: per_subnet_rate.filter(|it| !it.is_unlimited()),
+            exemptions: exemptions.iter().map(|it| (*it, ())).collect(),
+            sessions: ClientTable::new(max_tracked_clients),
+            client_buckets: ClientTable::new(max_tracked_clients),
+            subnet_buckets: ClientTable::new(max_tracked_clients),
+        }
+    }
+    /// Reserves a session for the client, unless it is over its connection rate or already has
+    /// the maximum number of sessions. Every successful acquire must be matched by a release.
+    pub fn acquire(&self, address: &IpAddr, now: Instant) -> bool {
+        if self.exemptions.contains(address) {
+            return true;
+        }
+        if let Some(ref limit) = self.per_client_rate {
+            if !self
+                .client_buckets
+                .update(address, now, |it| it.take(limit, now))
+            {
+                return false;
+            }
+        }
+        if let Some(ref limit) = self.per_subnet_rate {
+            let subnet = Self::subnet(address);
+            if !self
+                .subnet_buckets
+                .update(&subnet, now, |it| it.take(limit, now))
+            {
+                self.refund(address, false, now);
+                return false;
+            }
+        }
+        let acquired = match self.max_sessions {
+            Some(max_sessions) => self.sessions.update(address, now, |it| {
+                if it.active < max_sessions {
+                    it.active += 1;
+                    true
+                } else {
+                    false
+                }
+            }),
+            None => true,
+        };
+        if !acquired {
+            self.refund(address, true, now);
+        }
+        acquired
+    }
+    pub fn release(&self, address: &IpAddr, now: Instant) {
+        if self.max_sessions.is_some() && !self.exemptions.contains(address) {
+            self.sessions.update(address, now, |it| {
+                it.active = it.active.saturating_sub(1);
+            });
+        }
+    }
+    // a connection rejected by a limit doesn't spend the tokens already taken by the others, of
+    // the client and of its subnet
+    fn refund(&self, address: &IpAddr, subnet: bool, now: Instant) {
+        if let Some(ref limit) = self.per_client_rate {
+            self.client_buckets
+                .update(address, now, |it| it.refund(limit, now));
+        }
+        if let (true, Some(ref limit)) = (subnet, self.per_subnet_rate) {
+            self.subnet_buckets
+                .update(&Self::subnet(address), now, |it| it.refund(limit, now));
+        }
+    }
+    fn subnet(address: &IpAddr) -> IpAddr {
+        let address = normalize(*address);
+        let prefix_length = if address.is_ipv4() { 24 } else { 64 };
+        Cidr::new(address, prefix_length).unwrap().address()
+    }
+}
+
+/// What the listener does when the maximum number of concurrent sessions is reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum OverloadPolicy {
+    /// Stop accepting, new clients wait in the kernel backlog until a session ends.
+    Pause,
+    /// Keep accepting, and close the new connections immediately.
+    Close,
+}
+
+/// Global limit on concurrent sessions. Each session holds a slot until it ends.
+pub struct SessionLimit {
+    slots: Semaphore,
+    max_sessions: Option<usize>,
+    policy: OverloadPolicy,
+}
+
+impl SessionLimit {
+    pub fn new(max_sessions: Option<usize>, policy: OverloadPolicy) -> Self {
+        Self {
+            slots: Semaphore::new(max_sessions.unwrap_or(Semaphore::MAX_PERMITS)),
+            max_sessions,
+            policy,
+        }
+    }
+    pub fn policy(&self) -> OverloadPolicy {
+        self.policy
+    }
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        // the semaphore is never closed
+        self.slots.acquire().await.unwrap()
+    }
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.slots.try_acquire().ok()
+    }
+    pub fn max_sessions(&self) -> Option<usize> {
+        self.max_sessions
+    }
+    pub fn active_sessions(&self) -> usize {
+        self.max_sessions.unwrap_or(Semaphore::MAX_PERMITS) - self.slots.available_permits()
+    }
+}
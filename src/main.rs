@@ -5,9 +5,28 @@ use std::sync::atomic::{AtomicU64, Ordering};
 #[macro_use]
 extern crate lazy_static;
 
+mod access;
+mod admin;
+mod cache;
+mod cidr;
+mod clients;
+mod compression;
 mod conf;
 mod errors;
+mod failure;
+mod forwarded;
+mod headers;
+mod http;
+mod limits;
+mod pool;
+mod proxy;
+mod retry;
+mod rewrite;
+mod route;
+mod sni;
+mod sticky;
 mod tcp;
+mod tls;
 
 lazy_static! {
     static ref CONFIG: ConfImpl =
@@ -0,0 +1,307 @@
+use crate::conf::ToSocketAddr;
+use crate::http::IdleConnections;
+use crate::proxy::ProxyProtocol;
+use crate::tls::BackendTls;
+use crossbeam::sync::ShardedLock;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
+use tokio::time::Instant;
+
+const UNLIMITED: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unavailable {
+    NoBackend,
+    QueueFull,
+    QueueTimeout,
+}
+
+impl Display for Unavailable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::NoBackend => "NO BACKEND",
+            Self::QueueFull => "QUEUE FULL",
+            Self::QueueTimeout => "QUEUE TIMEOUT",
+        })
+    }
+}
+
+/// A set of interchangeable backends, each session going to the one with the fewest active
+/// sessions. When every backend has reached its maximum number of connections, clients wait in a
+/// bounded FIFO queue and are handed the slots of the sessions that end, in order. When no backend
+/// is available at all, clients are held for a grace period until one is added or enabled.
+pub struct Pool {
+    backends: ShardedLock<Vec<Arc<Backend>>>,
+    max_connections_per_backend: Option<u32>,
+    settings: Arc<BackendSettings>,
+    // notified when a backend is added or enabled
+    available: Notify,
+    no_backend_grace_period: Option<Duration>,
+    // waiting clients, a slot is transferred by sending them the backend
+    queue: Mutex<VecDeque<oneshot::Sender<Arc<Backend>>>>,
+    max_queue_length: usize,
+    queue_timeout: Duration,
+}
+
+enum Reservation {
+    Reserved(Arc<Backend>),
+    Queued(oneshot::Receiver<Arc<Backend>>),
+}
+
+impl Pool {
+    pub fn new(
+        max_connections_per_backend: Option<u32>,
+        max_queue_length: usize,
+        queue_timeout: Duration,
+        no_backend_grace_period: Option<Duration>,
+        settings: BackendSettings,
+    ) -> Self {
+        Self {
+            backends: ShardedLock::new(vec![]),
+            max_connections_per_backend,
+            settings: Arc::new(settings),
+            available: Notify::new(),
+            no_backend_grace_period,
+            queue: Mutex::new(VecDeque::new()),
+            max_queue_length,
+            queue_timeout,
+        }
+    }
+    pub fn settings(&self) -> &BackendSettings {
+        &self.settings
+    }
+    pub async fn select(&self) -> Result<Arc<Backend>, Unavailable> {
+        let deadline = self.no_backend_grace_period.map(|it| Instant::now() + it);
+        let mut receiver = loop {
+            // registered before looking at the backends so that no notification is missed
+            let available = self.available.notified();
+            tokio::pin!(available);
+            available.as_mut().enable();
+            match self.reserve() {
+                Ok(Reservation::Reserved(backend)) => return Ok(backend),
+                Ok(Reservation::Queued(receiver)) => break receiver,
+                Err(Unavailable::NoBackend) => match deadline {
+                    Some(deadline) => {
+                        if tokio::time::timeout_at(deadline, available).await.is_err() {
+                            return Err(Unavailable::NoBackend);
+                        }
+                    }
+                    None => return Err(Unavailable::NoBackend),
+                },
+                Err(reason) => return Err(reason),
+            }
+        };
+        match tokio::time::timeout(self.queue_timeout, &mut receiver).await {
+            Ok(Ok(backend)) => Ok(backend),
+            Ok(Err(_)) => Err(Unavailable::QueueTimeout),
+            Err(_) => {
+                // a slot might have been handed over right after the timeout
+                receiver.close();
+                receiver.try_recv().map_err(|_| Unavailable::QueueTimeout)
+            }
+        }
+    }
+    /// Reserves a slot on the least loaded backend accepting the predicate, without waiting.
+    /// Clients that are already waiting go first.
+    pub fn select_matching<F: Fn(&Backend) -> bool>(&self, predicate: F) -> Option<Arc<Backend>> {
+        let backends = self.backends.read().unwrap();
+        let mut queue = self.queue.lock().unwrap();
+        queue.retain(|it| !it.is_closed());
+        if !queue.is_empty() {
+            return None;
+        }
+        Self::reserve_least_loaded(backends.iter().filter(|it| predicate(it))).cloned()
+    }
+    fn reserve(&self) -> Result<Reservation, Unavailable> {
+        let backends = self.backends.read().unwrap();
+        if backends
+            .iter()
+            .all(|it| it.unavailable.load(Ordering::SeqCst))
+        {
+            return Err(Unavailable::NoBackend);
+        }
+        let mut queue = self.queue.lock().unwrap();
+        queue.retain(|it| !it.is_closed());
+        // clients that are already waiting go first
+        if queue.is_empty() {
+            if let Some(backend) = Self::reserve_least_loaded(backends.iter()) {
+                return Ok(Reservation::Reserved(backend.clone()));
+            }
+        }
+        if queue.len() >= self.max_queue_length {
+            return Err(Unavailable::QueueFull);
+        }
+        let (sender, receiver) = oneshot::channel();
+        queue.push_back(sender);
+        Ok(Reservation::Queued(receiver))
+    }
+    /// Gives the slot of a session that ended to the next waiting client, or frees it.
+    pub fn release(&self, backend: &Arc<Backend>) {
+        let backends = self.backends.read().unwrap();
+        let mut queue = self.queue.lock().unwrap();
+        if backend.active_counter.load(Ordering::SeqCst) as i64 <= backend.max_connections() as i64
+            && !backend.unavailable.load(Ordering::SeqCst)
+            && backends.iter().any(|it| Arc::ptr_eq(it, backend))
+        {
+            while let Some(waiting) = queue.pop_front() {
+                if waiting.send(backend.clone()).is_ok() {
+                    return;
+                }
+            }
+        }
+        backend.active_counter.fetch_sub(1, Ordering::SeqCst);
+    }
+    pub fn add_backend(&self, backend_address: SocketAddr) {
+        let mut backends = self.backends.write().unwrap();
+        if !backends.iter().any(|it| it.address == backend_address) {
+            backends.push(Arc::new(Backend::init(
+                backend_address,
+                self.max_connections_per_backend,
+                self.settings.clone(),
+            )));
+        }
+        Self::dispatch(&backends, &mut self.queue.lock().unwrap());
+        self.available.notify_waiters();
+    }
+    pub fn remove_backend(&self, backend_address: SocketAddr) {
+        let mut backends = self.backends.write().unwrap();
+        if let Some(pos) = backends.iter().position(|it| it.address == backend_address) {
+            backends.remove(pos);
+        }
+    }
+    pub fn set_max_connections(&self, backend_address: SocketAddr, max_connections: Option<u32>) {
+        let backends = self.backends.read().unwrap();
+        if let Some(backend) = backends.iter().find(|it| it.address == backend_address) {
+            backend
+                .max_connections
+                .store(max_connections.unwrap_or(UNLIMITED), Ordering::SeqCst);
+        }
+        Self::dispatch(&backends, &mut self.queue.lock().unwrap());
+    }
+    pub fn set_available(&self, backend_address: SocketAddr, available: bool) {
+        let backends = self.backends.read().unwrap();
+        if let Some(backend) = backends.iter().find(|it| it.address == backend_address) {
+            backend.unavailable.store(!available, Ordering::SeqCst);
+        }
+        if available {
+            Self::dispatch(&backends, &mut self.queue.lock().unwrap());
+            self.available.notify_waiters();
+        }
+    }
+    // least connections: the backends with the fewest active sessions are tried first, in order
+    fn reserve_least_loaded<'a, I: Iterator<Item = &'a Arc<Backend>>>(
+        backends: I,
+    ) -> Option<&'a Arc<Backend>> {
+        let mut backends: Vec<&Arc<Backend>> = backends.collect();
+        backends.sort_by_key(|it| it.active_counter.load(Ordering::SeqCst));
+        backends.into_iter().find(|it| it.try_reserve())
+    }
+    // hands out the free slots to the waiting clients, after backends were added or raised
+    fn dispatch(backends: &[Arc<Backend>], queue: &mut VecDeque<oneshot::Sender<Arc<Backend>>>) {
+        while !queue.is_empty() {
+            match Self::reserve_least_loaded(backends.iter()) {
+                Some(backend) => {
+                    let mut sent = false;
+                    while let Some(waiting) = queue.pop_front() {
+                        if waiting.send(backend.clone()).is_ok() {
+                            sent = true;
+                            break;
+                        }
+                    }
+                    if !sent {
+                        backend.active_counter.fetch_sub(1, Ordering::SeqCst);
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Settings shared by all the backends of a pool.
+#[derive(Default)]
+pub struct BackendSettings {
+    /// Name of the pool, none for the default one.
+    pub pool: Option<String>,
+    pub proxy_protocol: Option<ProxyProtocol>,
+    pub tls: Option<BackendTls>,
+    pub http2: bool,
+    /// Connections kept open for the next HTTP requests, per backend.
+    pub max_idle_connections: usize,
+    pub idle_connection_timeout: Option<Duration>,
+}
+
+pub struct Backend {
+    pub(crate) address: SocketAddr,
+    settings: Arc<BackendSettings>,
+    active_counter: AtomicI32,
+    max_connections: AtomicU32,
+    pub(crate) last_failure: AtomicU64, // secs
+    unavailable: AtomicBool,
+    idle_connections: IdleConnections,
+}
+
+impl ToSocketAddr for Arc<Backend> {
+    fn address(&self) -> &SocketAddr {
+        &self.address
+    }
+    fn proxy_protocol(&self) -> Option<&ProxyProtocol> {
+        self.settings.proxy_protocol.as_ref()
+    }
+    fn tls(&self) -> Option<&BackendTls> {
+        self.settings.tls.as_ref()
+    }
+    fn http2(&self) -> bool {
+        self.settings.http2
+    }
+    fn idle_connections(&self) -> Option<&IdleConnections> {
+        Some(&self.idle_connections)
+    }
+}
+
+impl Backend {
+    fn init(
+        address: SocketAddr,
+        max_connections: Option<u32>,
+        settings: Arc<BackendSettings>,
+    ) -> Self {
+        Self {
+            address,
+            active_counter: AtomicI32::new(0),
+            max_connections: AtomicU32::new(max_connections.unwrap_or(UNLIMITED)),
+            last_failure: AtomicU64::new(0),
+            unavailable: AtomicBool::new(false),
+            idle_connections: IdleConnections::new(
+                settings.max_idle_connections,
+                settings.idle_connection_timeout,
+            ),
+            settings,
+        }
+    }
+    pub(crate) fn pool(&self) -> Option<&str> {
+        self.settings.pool.as_deref()
+    }
+    fn max_connections(&self) -> u32 {
+        self.max_connections.load(Ordering::SeqCst)
+    }
+    fn try_reserve(&self) -> bool {
+        if self.unavailable.load(Ordering::SeqCst) {
+            return false;
+        }
+        let max_connections = self.max_connections() as i64;
+        self.active_counter
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |it| {
+                if (it as i64) < max_connections {
+                    Some(it + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+}
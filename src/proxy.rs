@@ -0,0 +1,269 @@
+use crate::cidr::normalize;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LENGTH: usize = 107;
+// version 2, PROXY command
+const V2_PROXY: u8 = 0x21;
+const V2_LOCAL: u8 = 0x20;
+const V2_UNSPEC: u8 = 0x00;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+// high nibble of the family byte
+const V2_AF_UNSPEC: u8 = 0x0;
+const V2_AF_INET: u8 = 0x1;
+const V2_AF_INET6: u8 = 0x2;
+const V2_AF_UNIX: u8 = 0x3;
+// low nibble of the family byte: unspecified, stream or datagram
+const V2_MAX_TRANSPORT: u8 = 0x2;
+
+/// HAProxy PROXY protocol header sent to the backends, so that they see the address of the client
+/// instead of the address of headmaster.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum ProxyProtocol {
+    V1,
+    V2 { tlvs: Vec<Tlv> },
+}
+
+/// PROXY protocol header received from an upstream load balancer. The addresses are missing for
+/// connections initiated by the balancer itself (health checks) and for unknown protocols.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+    pub tlvs: Vec<Tlv>,
+}
+
+/// Type-length-value extension of a PROXY protocol v2 header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tlv {
+    pub kind: u8,
+    pub value: Vec<u8>,
+}
+
+impl ProxyProtocol {
+    /// Encodes the header for a connection from `source` to `destination` (the listener address).
+    /// Fails if the v2 TLVs don't fit in the 16-bit lengths of the header.
+    pub fn header(
+        &self,
+        source: &SocketAddr,
+        destination: Option<&SocketAddr>,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        let addresses = destination.map(|destination| same_family(source, destination));
+        let header = match self {
+            Self::V1 => match addresses {
+                Some((source, destination)) => format!(
+                    "PROXY {} {} {} {} {}\r\n",
+                    if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                    source.ip(),
+                    destination.ip(),
+                    source.port(),
+                    destination.port()
+                )
+                .into_bytes(),
+                None => b"PROXY UNKNOWN\r\n".to_vec(),
+            },
+            Self::V2 { tlvs } => {
+                let mut payload = vec![];
+                let family = match addresses {
+                    Some((source, destination)) => {
+                        match (source.ip(), destination.ip()) {
+                            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                                payload.extend_from_slice(&source.octets());
+                                payload.extend_from_slice(&destination.octets());
+                            }
+                            (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                                payload.extend_from_slice(&source.octets());
+                                payload.extend_from_slice(&destination.octets());
+                            }
+                            _ => unreachable!(),
+                        }
+                        payload.extend_from_slice(&source.port().to_be_bytes());
+                        payload.extend_from_slice(&destination.port().to_be_bytes());
+                        if source.is_ipv4() {
+                            V2_TCP4
+                        } else {
+                            V2_TCP6
+                        }
+                    }
+                    None => V2_UNSPEC,
+                };
+                for tlv in tlvs {
+                    let length = u16::try_from(tlv.value.len()).map_err(|_| too_long())?;
+                    payload.push(tlv.kind);
+                    payload.extend_from_slice(&length.to_be_bytes());
+                    payload.extend_from_slice(&tlv.value);
+                }
+                let length = u16::try_from(payload.len()).map_err(|_| too_long())?;
+                let mut header = Vec::with_capacity(16 + payload.len());
+                header.extend_from_slice(V2_SIGNATURE);
+                header.push(V2_PROXY);
+                header.push(family);
+                header.extend_from_slice(&length.to_be_bytes());
+                header.extend_from_slice(&payload);
+                header
+            }
+        };
+        Ok(header)
+    }
+}
+
+// Both addresses need to be of the same family, IPv4 addresses are mapped to IPv6 if needed.
+fn same_family(source: &SocketAddr, destination: &SocketAddr) -> (SocketAddr, SocketAddr) {
+    let source = SocketAddr::new(normalize(source.ip()), source.port());
+    let destination = SocketAddr::new(normalize(destination.ip()), destination.port());
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(ip), IpAddr::V6(_)) => (
+            SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), source.port()),
+            destination,
+        ),
+        (IpAddr::V6(_), IpAddr::V4(ip)) => (
+            source,
+            SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), destination.port()),
+        ),
+        _ => (source, destination),
+    }
+}
+
+/// Reads a v1 or v2 header, without consuming any of the data that follows it.
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<ProxyHeader, std::io::Error> {
+    let mut header = [0u8; 12];
+    stream.read_exact(&mut header).await?;
+    if &header == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if header.starts_with(b"PROXY ") {
+        let mut line = header.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LENGTH {
+                return Err(invalid("header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line[6..line.len() - 2])
+    } else {
+        Err(invalid("missing header"))
+    }
+}
+
+fn parse_v1(line: &[u8]) -> Result<ProxyHeader, std::io::Error> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("invalid v1 header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        [protocol @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            // both addresses are of the family of the protocol
+            let address = |ip: &str, port: &str| -> Result<SocketAddr, std::io::Error> {
+                let ip: IpAddr = ip.parse().map_err(|_| invalid("invalid v1 address"))?;
+                if ip.is_ipv4() != (*protocol == "TCP4") {
+                    return Err(invalid("invalid v1 address family"));
+                }
+                Ok(SocketAddr::new(
+                    ip,
+                    port.parse().map_err(|_| invalid("invalid v1 port"))?,
+                ))
+            };
+            Ok(ProxyHeader {
+                source: Some(address(source, source_port)?),
+                destination: Some(address(destination, destination_port)?),
+                tlvs: vec![],
+            })
+        }
+        ["UNKNOWN", ..] => Ok(ProxyHeader {
+            source: None,
+            destination: None,
+            tlvs: vec![],
+        }),
+        _ => Err(invalid("invalid v1 header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<ProxyHeader, std::io::Error> {
+    let command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let length = stream.read_u16().await? as usize;
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).await?;
+    if command != V2_PROXY && command != V2_LOCAL {
+        return Err(invalid("invalid v2 command"));
+    }
+    // the high nibble of the family is the address family, the low nibble is the transport
+    if family & 0x0f > V2_MAX_TRANSPORT {
+        return Err(invalid("invalid v2 transport"));
+    }
+    let address_length = match family >> 4 {
+        V2_AF_UNSPEC => 0,
+        V2_AF_INET => 12,
+        V2_AF_INET6 => 36,
+        V2_AF_UNIX => 216,
+        _ => return Err(invalid("invalid v2 address family")),
+    };
+    if payload.len() < address_length {
+        return Err(invalid("truncated v2 header"));
+    }
+    let (addresses, mut remaining) = payload.split_at(address_length);
+    let addresses = match family >> 4 {
+        V2_AF_INET => {
+            let ip = |i: usize| {
+                IpAddr::V4(Ipv4Addr::new(
+                    addresses[i],
+                    addresses[i + 1],
+                    addresses[i + 2],
+                    addresses[i + 3],
+                ))
+            };
+            let port = |i: usize| u16::from_be_bytes([addresses[i], addresses[i + 1]]);
+            Some((
+                SocketAddr::new(ip(0), port(8)),
+                SocketAddr::new(ip(4), port(10)),
+            ))
+        }
+        V2_AF_INET6 => {
+            let ip = |i: usize| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&addresses[i..i + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            let port = |i: usize| u16::from_be_bytes([addresses[i], addresses[i + 1]]);
+            Some((
+                SocketAddr::new(ip(0), port(32)),
+                SocketAddr::new(ip(16), port(34)),
+            ))
+        }
+        // no IP addresses (AF_UNSPEC, AF_UNIX), the peer address of the socket is used instead
+        _ => None,
+    };
+    let mut tlvs = vec![];
+    while !remaining.is_empty() {
+        if remaining.len() < 3 {
+            return Err(invalid("truncated v2 tlv"));
+        }
+        let value_length = u16::from_be_bytes([remaining[1], remaining[2]]) as usize;
+        if remaining.len() < 3 + value_length {
+            return Err(invalid("truncated v2 tlv"));
+        }
+        tlvs.push(Tlv {
+            kind: remaining[0],
+            value: remaining[3..3 + value_length].to_vec(),
+        });
+        remaining = &remaining[3 + value_length..];
+    }
+    // LOCAL connections are initiated by the balancer itself, the addresses are not relevant
+    let addresses = if command == V2_LOCAL { None } else { addresses };
+    Ok(ProxyHeader {
+        source: addresses.map(|it| it.0),
+        destination: addresses.map(|it| it.1),
+        tlvs,
+    })
+}
+
+fn invalid(reason: &str) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, reason)
+}
+
+fn too_long() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidInput, "PROXY protocol TLVs too long")
+}
@@ -0,0 +1,95 @@
+use http::header::HeaderName;
+use http::request::Parts;
+use http::{Method, StatusCode};
+use std::sync::Mutex;
+
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Sends the idempotent HTTP requests again, to another backend, when theirs fails before
+/// answering or answers with one of the statuses. Requests are idempotent when their method is,
+/// or when they have an Idempotency-Key header.
+#[derive(Clone, Debug)]
+pub struct RetrySettings {
+    /// Retries of a request, each one on a backend that wasn't tried yet.
+    pub max_retries: usize,
+    pub statuses: Vec<StatusCode>,
+    /// Retries allowed for each request, on average: 0.1 allows one retry every 10 requests.
+    pub budget_ratio: f64,
+    /// Retries saved up while the requests succeed, and allowed at startup.
+    pub budget_max: f64,
+    /// Requests with a bigger body, or a body of unknown size, are not retried. The bodies of the
+    /// other requests are read before they are sent, so that they can be sent again.
+    pub max_body_size: u64,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            max_retries: 1,
+            statuses: vec![
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ],
+            budget_ratio: 0.1,
+            budget_max: 10.0,
+            max_body_size: 65_536,
+        }
+    }
+}
+
+/// The settings, and the budget shared by all the requests so that failing backends don't get
+/// a retry for every request (and the others twice the load).
+pub struct Retries {
+    settings: RetrySettings,
+    budget: Mutex<f64>,
+}
+
+impl Retries {
+    pub fn new(settings: RetrySettings) -> Self {
+        Self {
+            budget: Mutex::new(settings.budget_max),
+            settings,
+        }
+    }
+    pub fn settings(&self) -> &RetrySettings {
+        &self.settings
+    }
+    /// Whether the request can be sent again, the size of its body being known.
+    pub fn applies(&self, request: &Parts, body_size: Option<u64>) -> bool {
+        let idempotent = matches!(
+            request.method,
+            Method::GET
+                | Method::HEAD
+                | Method::OPTIONS
+                | Method::PUT
+                | Method::DELETE
+                | Method::TRACE
+        ) || request.headers.contains_key(IDEMPOTENCY_KEY);
+        self.settings.max_retries > 0
+            && idempotent
+            && body_size
+                .map(|it| it <= self.settings.max_body_size)
+                .unwrap_or(false)
+    }
+    /// Adds the share of a request to the budget.
+    pub fn deposit(&self) {
+        let mut budget = self.budget.lock().unwrap();
+        *budget = (*budget + self.settings.budget_ratio).min(self.settings.budget_max);
+    }
+    /// Takes a retry from the budget, if there is one left.
+    pub fn withdraw(&self) -> bool {
+        let mut budget = self.budget.lock().unwrap();
+        if *budget >= 1.0 {
+            *budget -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+    /// Gives back a retry that couldn't be made.
+    pub fn refund(&self) {
+        let mut budget = self.budget.lock().unwrap();
+        *budget = (*budget + 1.0).min(self.settings.budget_max);
+    }
+}
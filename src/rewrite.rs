@@ -0,0 +1,147 @@
+use crate::route::{host, host_matches, PathMatch};
+use http::request::Parts;
+use http::uri::PathAndQuery;
+use http::{StatusCode, Uri};
+use regex::Regex;
+
+#[derive(Clone, Debug)]
+#[allow(dead_code)]
+pub enum UrlAction {
+    /// Answers with this status (301, 302, 307 or 308) and the expanded location, without
+    /// forwarding the request.
+    Redirect(StatusCode, String),
+    /// Replaces the path of the request with the expanded one. The query is kept unless the new
+    /// path has one.
+    Rewrite(String),
+}
+
+/// Redirects or rewrites the HTTP requests matching the conditions, before they are routed to a
+/// backend. The location and the path are templates: `{host}`, `{path}` and `{query}` (with its
+/// "?", if any) are replaced with the ones of the request, and `$1`, `$name`... with the groups
+/// of the path regex.
+#[derive(Clone, Debug)]
+pub struct UrlRule {
+    pub action: UrlAction,
+    /// Same as the host of `HttpRoute`.
+    pub host: Option<String>,
+    pub path: Option<PathMatch>,
+    /// Only the requests of clients that didn't connect with TLS.
+    pub plaintext: bool,
+}
+
+/// Where a request goes after the rules are applied.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Forward,
+    Redirect(StatusCode, String),
+}
+
+impl UrlRule {
+    #[allow(dead_code)]
+    pub fn redirect(status: StatusCode, location: &str) -> Self {
+        Self::new(UrlAction::Redirect(status, location.to_string()))
+    }
+    #[allow(dead_code)]
+    pub fn rewrite(path: &str) -> Self {
+        Self::new(UrlAction::Rewrite(path.to_string()))
+    }
+    #[allow(dead_code)]
+    fn new(action: UrlAction) -> Self {
+        Self {
+            action,
+            host: None,
+            path: None,
+            plaintext: false,
+        }
+    }
+    #[allow(dead_code)]
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_ascii_lowercase());
+        self
+    }
+    #[allow(dead_code)]
+    pub fn path_prefix(mut self, prefix: &str) -> Self {
+        self.path = Some(PathMatch::Prefix(prefix.to_string()));
+        self
+    }
+    #[allow(dead_code)]
+    pub fn path_regex(mut self, regex: Regex) -> Self {
+        self.path = Some(PathMatch::Regex(regex));
+        self
+    }
+    #[allow(dead_code)]
+    pub fn plaintext(mut self) -> Self {
+        self.plaintext = true;
+        self
+    }
+    pub fn matches(&self, request: &Parts, tls: bool) -> bool {
+        !(self.plaintext && tls)
+            && self
+                .host
+                .as_ref()
+                .map(|it| host_matches(it, request))
+                .unwrap_or(true)
+            && self
+                .path
+                .as_ref()
+                .map(|it| it.matches(request))
+                .unwrap_or(true)
+    }
+    fn expand(&self, template: &str, request: &Parts) -> String {
+        let path = request.uri.path();
+        let mut expanded = String::new();
+        match self.path {
+            Some(PathMatch::Regex(ref regex)) => match regex.captures(path) {
+                Some(captures) => captures.expand(template, &mut expanded),
+                None => expanded.push_str(template),
+            },
+            _ => expanded.push_str(template),
+        }
+        let query = request
+            .uri
+            .query()
+            .map(|it| format!("?{}", it))
+            .unwrap_or_default();
+        expanded
+            .replace("{host}", &host(request).unwrap_or_default())
+            .replace("{path}", path)
+            .replace("{query}", &query)
+    }
+}
+
+/// Applies the matching rules in order, each one to the request as rewritten by the previous
+/// ones, until one redirects.
+pub fn apply(rules: &[&UrlRule], request: &mut Parts, tls: bool) -> Outcome {
+    for rule in rules {
+        if !rule.matches(request, tls) {
+            continue;
+        }
+        match rule.action {
+            UrlAction::Redirect(status, ref location) => {
+                return Outcome::Redirect(status, rule.expand(location, request));
+            }
+            UrlAction::Rewrite(ref path) => {
+                let mut path = rule.expand(path, request);
+                if !path.contains('?') {
+                    if let Some(query) = request.uri.query() {
+                        path = format!("{}?{}", path, query);
+                    }
+                }
+                // an invalid path leaves the request as is
+                let Some(path_and_query) = path
+                    .parse::<PathAndQuery>()
+                    .ok()
+                    .filter(|_| path.starts_with('/'))
+                else {
+                    continue;
+                };
+                let mut uri = request.uri.clone().into_parts();
+                uri.path_and_query = Some(path_and_query);
+                if let Ok(uri) = Uri::from_parts(uri) {
+                    request.uri = uri;
+                }
+            }
+        }
+    }
+    Outcome::Forward
+}
@@ -0,0 +1,114 @@
+use crate::headers::HeaderRule;
+use crate::rewrite::UrlRule;
+use http::request::Parts;
+use regex::Regex;
+
+/// Sends the HTTP requests matching the host and path conditions to a named pool. Routes are
+/// evaluated in order, requests that don't match any route go to the default pool.
+#[derive(Clone, Debug)]
+pub struct HttpRoute {
+    pub pool: String,
+    /// Exact host name, or "*." followed by a domain to match any single label of that domain.
+    pub host: Option<String>,
+    pub path: Option<PathMatch>,
+    /// Applied to the requests sent to the pool and to their responses, after the global ones.
+    pub header_rules: Vec<HeaderRule>,
+    /// Applied in order to the requests matching the route, after the global ones.
+    pub url_rules: Vec<UrlRule>,
+}
+
+#[derive(Clone, Debug)]
+#[allow(dead_code)]
+pub enum PathMatch {
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl HttpRoute {
+    #[allow(dead_code)]
+    pub fn to_pool(pool: &str) -> Self {
+        Self {
+            pool: pool.to_string(),
+            host: None,
+            path: None,
+            header_rules: vec![],
+            url_rules: vec![],
+        }
+    }
+    #[allow(dead_code)]
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_ascii_lowercase());
+        self
+    }
+    #[allow(dead_code)]
+    pub fn path_prefix(mut self, prefix: &str) -> Self {
+        self.path = Some(PathMatch::Prefix(prefix.to_string()));
+        self
+    }
+    #[allow(dead_code)]
+    pub fn path_regex(mut self, regex: Regex) -> Self {
+        self.path = Some(PathMatch::Regex(regex));
+        self
+    }
+    #[allow(dead_code)]
+    pub fn header_rule(mut self, rule: HeaderRule) -> Self {
+        self.header_rules.push(rule);
+        self
+    }
+    #[allow(dead_code)]
+    pub fn url_rule(mut self, rule: UrlRule) -> Self {
+        self.url_rules.push(rule);
+        self
+    }
+    pub fn matches(&self, request: &Parts) -> bool {
+        self.host
+            .as_ref()
+            .map(|it| host_matches(it, request))
+            .unwrap_or(true)
+            && self
+                .path
+                .as_ref()
+                .map(|it| it.matches(request))
+                .unwrap_or(true)
+    }
+}
+
+impl PathMatch {
+    pub fn matches(&self, request: &Parts) -> bool {
+        match self {
+            Self::Prefix(prefix) => request.uri.path().starts_with(prefix),
+            Self::Regex(regex) => regex.is_match(request.uri.path()),
+        }
+    }
+}
+
+/// Whether the host of the request is the expected one, "*." followed by a domain matching any
+/// single label of that domain.
+pub fn host_matches(expected: &str, request: &Parts) -> bool {
+    match host(request) {
+        Some(host) => match expected.strip_prefix("*.") {
+            Some(domain) => host
+                .split_once('.')
+                .map(|(_, it)| it == domain)
+                .unwrap_or(false),
+            None => host == expected,
+        },
+        None => false,
+    }
+}
+
+/// Host of the request without the port, from the absolute URI or else the Host header.
+pub fn host(request: &Parts) -> Option<String> {
+    let host = match request.uri.host() {
+        Some(host) => host,
+        None => {
+            let host = request.headers.get(http::header::HOST)?.to_str().ok()?;
+            // IPv6 literals are in brackets, the port is after the last colon
+            match host.rfind(':') {
+                Some(i) if !host[i..].contains(']') => &host[..i],
+                _ => host,
+            }
+        }
+    };
+    Some(host.to_ascii_lowercase())
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const RECORD_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const EXTENSION_ALPN: u16 = 0x0010;
+// a client hello split over more records than this is not worth waiting for
+const MAX_CLIENT_HELLO_LENGTH: usize = 65_536;
+
+/// What the client asked for in its TLS ClientHello.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    pub server_name: Option<String>,
+    pub alpn_protocols: Vec<Vec<u8>>,
+}
+
+/// Reads the records carrying the ClientHello without terminating TLS. The bytes that were read
+/// are returned as well, so that they can be replayed to the backend. Streams that don't start
+/// with a TLS handshake are not an error, they simply don't have a ClientHello.
+pub async fn peek_client_hello<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<(Option<ClientHello>, Vec<u8>), std::io::Error> {
+    let mut buffered = vec![];
+    let mut handshake = vec![];
+    loop {
+        let mut header = [0u8; 5];
+        let n = read_some(stream, &mut header, &mut buffered).await?;
+        if n < header.len() || header[0] != RECORD_HANDSHAKE {
+            return Ok((None, buffered));
+        }
+        let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let mut fragment = vec![0u8; length];
+        if read_some(stream, &mut fragment, &mut buffered).await? < length {
+            return Ok((None, buffered));
+        }
+        handshake.extend_from_slice(&fragment);
+        if handshake.len() >= 4 {
+            if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+                return Ok((None, buffered));
+            }
+            let length = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake.len() >= 4 + length {
+                let client_hello = parse_client_hello(&handshake[4..4 + length]);
+                return Ok((client_hello, buffered));
+            }
+            if length > MAX_CLIENT_HELLO_LENGTH {
+                return Ok((None, buffered));
+            }
+        }
+    }
+}
+
+// Reads until the buffer is full or the stream ends, keeping a copy of everything read.
+async fn read_some<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut [u8],
+    buffered: &mut Vec<u8>,
+) -> Result<usize, std::io::Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = stream.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        buffered.extend_from_slice(&buf[filled..filled + n]);
+        filled += n;
+    }
+    Ok(filled)
+}
+
+fn parse_client_hello(body: &[u8]) -> Option<ClientHello> {
+    let mut reader = Reader(body);
+    // legacy version and random
+    reader.skip(2 + 32)?;
+    let session_id_length = reader.u8()? as usize;
+    reader.skip(session_id_length)?;
+    let cipher_suites_length = reader.u16()? as usize;
+    reader.skip(cipher_suites_length)?;
+    let compression_methods_length = reader.u8()? as usize;
+    reader.skip(compression_methods_length)?;
+    let mut client_hello = ClientHello::default();
+    if reader.0.is_empty() {
+        return Some(client_hello);
+    }
+    let extensions_length = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_length)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let length = extensions.u16()? as usize;
+        let mut extension = Reader(extensions.take(length)?);
+        match kind {
+            EXTENSION_SERVER_NAME => {
+                let length = extension.u16()? as usize;
+                let mut names = Reader(extension.take(length)?);
+                while !names.0.is_empty() {
+                    let name_type = names.u8()?;
+                    let length = names.u16()? as usize;
+                    let name = names.take(length)?;
+                    // 0 is the only name type, host_name
+                    if name_type == 0 {
+                        client_hello.server_name =
+                            Some(std::str::from_utf8(name).ok()?.to_ascii_lowercase());
+                    }
+                }
+            }
+            EXTENSION_ALPN => {
+                let length = extension.u16()? as usize;
+                let mut protocols = Reader(extension.take(length)?);
+                while !protocols.0.is_empty() {
+                    let length = protocols.u8()? as usize;
+                    client_hello
+                        .alpn_protocols
+                        .push(protocols.take(length)?.to_vec());
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(client_hello)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|it| it[0])
+    }
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|it| u16::from_be_bytes([it[0], it[1]]))
+    }
+}
+
+/// Values keyed by host name. Names starting with "*." match any single label, exact names take
+/// precedence over wildcards.
+#[derive(Debug)]
+pub struct HostMap<V> {
+    exact: HashMap<String, V>,
+    // keyed by the name without the "*." prefix
+    wildcard: HashMap<String, V>,
+}
+
+impl<V> Default for HostMap<V> {
+    fn default() -> Self {
+        Self {
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+        }
+    }
+}
+
+impl<V> HostMap<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcard.is_empty()
+    }
+    pub fn insert(&mut self, name: &str, value: V) {
+        let name = name.to_ascii_lowercase();
+        match name.strip_prefix("*.") {
+            Some(domain) => self.wildcard.insert(domain.to_string(), value),
+            None => self.exact.insert(name, value),
+        };
+    }
+    pub fn get(&self, name: &str) -> Option<&V> {
+        let name = name.to_ascii_lowercase();
+        self.exact.get(&name).or_else(|| {
+            name.split_once('.')
+                .and_then(|(_, domain)| self.wildcard.get(domain))
+        })
+    }
+}
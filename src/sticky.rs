@@ -0,0 +1,76 @@
+use http::header::COOKIE;
+use http::request::Parts;
+use ring::hmac;
+use std::net::SocketAddr;
+
+/// Cookie naming the backend that served a client, so that its next requests go to the same
+/// backend. The value is a signature of the backend address: it doesn't reveal the address, and
+/// it can't be forged without the secret.
+#[derive(Clone)]
+pub struct StickyCookie {
+    name: String,
+    key: hmac::Key,
+}
+
+impl StickyCookie {
+    pub fn new(name: &str, secret: &[u8]) -> Self {
+        Self {
+            name: name.to_string(),
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+    #[allow(dead_code)]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// Value of the cookie for this backend.
+    pub fn token(&self, backend_address: &SocketAddr) -> String {
+        let tag = hmac::sign(&self.key, backend_address.to_string().as_bytes());
+        tag.as_ref()
+            .iter()
+            .map(|it| format!("{:02x}", it))
+            .collect()
+    }
+    /// Whether the cookie value was issued for this backend.
+    pub fn matches(&self, token: &str, backend_address: &SocketAddr) -> bool {
+        match decode_hex(token) {
+            Some(tag) => hmac::verify(
+                &self.key,
+                backend_address.to_string().as_bytes(),
+                tag.as_slice(),
+            )
+            .is_ok(),
+            None => false,
+        }
+    }
+    /// Value of the cookie sent with the request, if any.
+    pub fn value<'a>(&self, request: &'a Parts) -> Option<&'a str> {
+        request
+            .headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|it| it.to_str().ok())
+            .flat_map(|it| it.split(';'))
+            .filter_map(|it| it.trim().split_once('='))
+            .find(|(name, _)| *name == self.name)
+            .map(|(_, value)| value)
+    }
+    /// Set-Cookie header value sticking the client to this backend.
+    pub fn set_cookie(&self, backend_address: &SocketAddr) -> String {
+        format!(
+            "{}={}; Path=/; HttpOnly",
+            self.name,
+            self.token(backend_address)
+        )
+    }
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) || !value.is_ascii() {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok())
+        .collect()
+}
@@ -1,18 +1,51 @@
-use crate::conf::{BindAddress, Conf, ToSocketAddr};
+use crate::admin;
+use crate::conf::{BindAddress, Conf, Metadata, Protocol, ToSocketAddr};
 use crate::errors::Error;
+use crate::http;
+use crate::limits::OverloadPolicy;
+use crate::pool::Unavailable;
+use crate::proxy;
+use crate::sni::{self, ClientHello};
+use crate::tls;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::tcp::{ReadHalf, WriteHalf};
 use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::client;
+use tokio_rustls::server::TlsStream;
 
-pub async fn connect<B: ToSocketAddr + Sync + Send, C: Conf<B> + Sync>(
+pub async fn connect<B: ToSocketAddr + Sync + Send + Unpin + 'static, C: Conf<B> + Sync>(
     config: &'static C,
 ) -> Result<(), Error> {
-    accept_loop(config, bind(config).await?).await
+    let listener = bind(config).await?;
+    // the proxy keeps running without the admin protocol when its address can't be bound
+    let admin_listener = admin::bind(config)
+        .await
+        .map_err(|e| eprintln!("ADMIN BIND FAILURE\n{}", e))
+        .ok();
+    tokio::try_join!(
+        accept_loop(config, listener),
+        async {
+            match admin_listener {
+                Some(admin_listener) => admin::admin_loop(config, admin_listener).await,
+                None => Ok(()),
+            }
+        },
+        tls::reload_loop(config)
+    )?;
+    Ok(())
 }
 
 pub async fn bind<B: ToSocketAddr + Sync + Send, C: Conf<B> + Sync>(
     config: &'static C,
 ) -> Result<SocketListener, Error> {
+    config.load_certificates()?;
+    config.load_error_responses()?;
     config
         .bind_address()
         .bind()
@@ -20,82 +53,464 @@ pub async fn bind<B: ToSocketAddr + Sync + Send, C: Conf<B> + Sync>(
         .map_err(|err| Error::from(err))
 }
 
-pub async fn accept_loop<'a, B: ToSocketAddr + Sync + Send, C: Conf<B> + Sync>(
+pub async fn accept_loop<'a, B: ToSocketAddr + Sync + Send + Unpin + 'static, C: Conf<B> + Sync>(
     config: &'static C,
     listener: SocketListener,
 ) -> Result<(), Error> {
+    let limit = config.session_limit();
     loop {
+        // with the pause policy, the slot is reserved before accepting,
+        // so that new clients wait in the backlog while the limit is reached
+        let reserved = if limit.policy() == OverloadPolicy::Pause {
+            Some(limit.acquire().await)
+        } else {
+            None
+        };
         if let Ok((mut client_stream, remote_address)) = listener.accept().await {
+            let slot = match reserved.or_else(|| limit.try_acquire()) {
+                Some(slot) => slot,
+                None => {
+                    eprintln!("{} => OVERLOADED", remote_address);
+                    continue;
+                }
+            };
             tokio::spawn(async move {
+                let _slot = slot;
+                let mut local_address = client_stream.local_addr().ok();
+                let remote_address = if config.expects_proxy_protocol(&remote_address) {
+                    match timeout(
+                        config.read_timeout(),
+                        proxy::read_header(&mut client_stream),
+                    )
+                    .await
+                    {
+                        Ok(header) => {
+                            local_address = header.destination.or(local_address);
+                            header.source.unwrap_or(remote_address)
+                        }
+                        Err(e) => {
+                            eprintln!("{} => INVALID PROXY HEADER\n{}", remote_address, e);
+                            return;
+                        }
+                    }
+                } else {
+                    remote_address
+                };
                 if let Some(trace) = config.accept(&remote_address) {
-                    if let Some(backend_address) = config.select(&remote_address, trace) {
-                        match TcpStream::connect(backend_address.address()).await {
-                            Ok(mut backend_stream) => {
-                                let (mut client_stream_read, mut client_stream_write) =
-                                    client_stream.split();
-                                let (mut backend_stream_read, mut backend_stream_write) =
-                                    backend_stream.split();
-                                let read_request = async {
-                                    client_stream_read
-                                        .copy_to(&mut backend_stream_write)
-                                        .await
-                                        .map_err(|e| CopyError::ReadFailure(e))
-                                };
-                                let write_response = async {
-                                    client_stream_write
-                                        .copy_from(&mut backend_stream_read)
-                                        .await
-                                        .map_err(|e| CopyError::WriteFailure(e))
-                                };
-                                match tokio::try_join!(read_request, write_response) {
-                                    Ok((request_size, response_size)) => config.record_success(
-                                        &remote_address,
-                                        backend_address,
-                                        request_size,
-                                        response_size,
-                                        trace,
-                                    ),
-                                    Err(e) => match e {
-                                        CopyError::ReadFailure(e) => {
-                                            config.record_read_failure(
-                                                &remote_address,
-                                                backend_address,
-                                                e,
-                                                trace,
-                                            );
-                                        }
-                                        CopyError::WriteFailure(e) => {
-                                            config.record_write_failure(
-                                                &remote_address,
-                                                backend_address,
-                                                e,
-                                                trace,
-                                            );
-                                        }
-                                    },
-                                }
+                    match handshake(config, client_stream).await {
+                        Ok((client_stream, client_hello, buffered)) => match config.protocol() {
+                            Protocol::Tcp => {
+                                session(
+                                    config,
+                                    client_stream,
+                                    &remote_address,
+                                    local_address.as_ref(),
+                                    client_hello.as_ref(),
+                                    &buffered,
+                                    trace,
+                                )
+                                .await
                             }
-                            Err(e) => config.record_connection_failure(
-                                &remote_address,
-                                backend_address,
-                                e,
-                                trace,
-                            ),
+                            Protocol::Http => {
+                                http::session(
+                                    config,
+                                    client_stream,
+                                    &remote_address,
+                                    local_address.as_ref(),
+                                    client_hello.as_ref(),
+                                    trace,
+                                )
+                                .await
+                            }
+                        },
+                        Err(e) => {
+                            eprintln!("{} => TLS HANDSHAKE FAILURE\n{}", remote_address, e);
+                            config.record_client_failure(&remote_address);
                         }
                     }
+                    config.release(&remote_address, trace);
                 }
             });
         }
     }
 }
 
-enum CopyError {
-    ReadFailure(std::io::Error),
-    WriteFailure(std::io::Error),
+// Terminates TLS if the listener is configured for it, or else peeks at the ClientHello if it is
+// needed for routing. The bytes read while peeking are returned, to be replayed to the backend.
+async fn handshake<B: ToSocketAddr + Sync + Send, C: Conf<B> + Sync>(
+    config: &'static C,
+    client_stream: AcceptStream,
+) -> Result<(AcceptStream, Option<ClientHello>, Vec<u8>), std::io::Error> {
+    match (config.tls(), client_stream) {
+        (Some(tls), AcceptStream::Tcp(stream)) => {
+            let stream = timeout(config.read_timeout(), tls.accept(stream)).await?;
+            let (_, connection) = stream.get_ref();
+            let client_hello = ClientHello {
+                server_name: connection.server_name().map(|it| it.to_string()),
+                alpn_protocols: connection
+                    .alpn_protocol()
+                    .map(|it| vec![it.to_vec()])
+                    .unwrap_or_default(),
+            };
+            Ok((
+                AcceptStream::Tls(Box::new(stream)),
+                Some(client_hello),
+                vec![],
+            ))
+        }
+        (_, mut client_stream) if config.routes_by_server_name() => {
+            let (client_hello, buffered) = timeout(
+                config.read_timeout(),
+                sni::peek_client_hello(&mut client_stream),
+            )
+            .await?;
+            Ok((client_stream, client_hello, buffered))
+        }
+        (_, client_stream) => Ok((client_stream, None, vec![])),
+    }
+}
+
+pub(crate) async fn timeout<T, F: Future<Output = Result<T, std::io::Error>>>(
+    duration: Option<Duration>,
+    future: F,
+) -> Result<T, std::io::Error> {
+    match duration {
+        Some(duration) => tokio::time::timeout(duration, future)
+            .await
+            .unwrap_or_else(|e| Err(e.into())),
+        None => future.await,
+    }
+}
+
+async fn session<B: ToSocketAddr + Sync + Send, C: Conf<B> + Sync>(
+    config: &'static C,
+    mut client_stream: AcceptStream,
+    remote_address: &SocketAddr,
+    local_address: Option<&SocketAddr>,
+    client_hello: Option<&ClientHello>,
+    buffered: &[u8],
+    trace: C::Trace,
+) {
+    let metadata = Metadata {
+        client_hello,
+        request: None,
+    };
+    match config.select(remote_address, metadata, trace).await {
+        Ok(backend_address) => {
+            match connect_backend(&backend_address, remote_address, local_address, buffered).await {
+                Ok(mut backend_stream) => {
+                    let copied = match backend_stream {
+                        BackendStream::Tcp(ref mut stream) => {
+                            let (mut read, mut write) = stream.split();
+                            copy(&mut client_stream, &mut read, &mut write).await
+                        }
+                        BackendStream::Tls(ref mut stream) => {
+                            let (mut read, mut write) = tokio::io::split(stream.as_mut());
+                            copy(&mut client_stream, &mut read, &mut write).await
+                        }
+                    };
+                    match copied {
+                        Ok((request_size, response_size)) => config.record_success(
+                            remote_address,
+                            backend_address,
+                            request_size,
+                            response_size,
+                            trace,
+                        ),
+                        Err(e) => match e {
+                            CopyError::ReadFailure(e, side) => {
+                                if side == Side::Client {
+                                    config.record_client_failure(remote_address);
+                                }
+                                config.record_read_failure(
+                                    remote_address,
+                                    backend_address,
+                                    e,
+                                    trace,
+                                );
+                            }
+                            CopyError::WriteFailure(e, side) => {
+                                if side == Side::Client {
+                                    config.record_client_failure(remote_address);
+                                }
+                                config.record_write_failure(
+                                    remote_address,
+                                    backend_address,
+                                    e,
+                                    trace,
+                                );
+                            }
+                        },
+                    }
+                }
+                Err(e) => {
+                    config.record_connection_failure(remote_address, backend_address, e, trace)
+                }
+            }
+        }
+        Err(Unavailable::NoBackend) => config.record_no_backend(remote_address, trace),
+        Err(Unavailable::QueueFull) => config.record_queue_full(remote_address, trace),
+        Err(Unavailable::QueueTimeout) => config.record_queue_timeout(remote_address, trace),
+    }
+}
+
+async fn copy<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    client_stream: &mut AcceptStream,
+    backend_stream_read: &mut R,
+    backend_stream_write: &mut W,
+) -> Result<(u64, u64), CopyError> {
+    let (mut client_stream_read, mut client_stream_write) = client_stream.split();
+    let mut backend_stream_read = Watched::new(backend_stream_read);
+    let mut backend_stream_write = Watched::new(backend_stream_write);
+    let read_request = async {
+        let copied = client_stream_read.copy_to(&mut backend_stream_write).await;
+        copied.map_err(|e| CopyError::ReadFailure(e, backend_stream_write.side()))
+    };
+    let write_response = async {
+        let copied = client_stream_write
+            .copy_from(&mut backend_stream_read)
+            .await;
+        copied.map_err(|e| CopyError::WriteFailure(e, backend_stream_read.side()))
+    };
+    tokio::try_join!(read_request, write_response)
+}
+
+/// Copies both ways between streams that are not split as the listener ones, such as upgraded
+/// HTTP connections. The copy fails once nothing was copied either way for the idle timeout,
+/// which is not blamed on the client.
+pub(crate) async fn copy_bidirectional<C, S>(
+    client_stream: C,
+    backend_stream: S,
+    idle_timeout: Option<Duration>,
+) -> Result<(u64, u64), CopyError>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_stream_read, mut client_stream_write) = tokio::io::split(client_stream);
+    let (backend_stream_read, backend_stream_write) = tokio::io::split(backend_stream);
+    let mut backend_stream_read = Watched::new(backend_stream_read);
+    let mut backend_stream_write = Watched::new(backend_stream_write);
+    let last_activity = Mutex::new(Instant::now());
+    let side = |e: &std::io::Error, failed: Side| {
+        if e.kind() == std::io::ErrorKind::TimedOut {
+            Side::Backend
+        } else {
+            failed
+        }
+    };
+    let read_request = async {
+        let copied = relay(
+            &mut client_stream_read,
+            &mut backend_stream_write,
+            idle_timeout,
+            &last_activity,
+        )
+        .await;
+        copied.map_err(|e| {
+            let side = side(&e, backend_stream_write.side());
+            CopyError::ReadFailure(e, side)
+        })
+    };
+    let write_response = async {
+        let copied = relay(
+            &mut backend_stream_read,
+            &mut client_stream_write,
+            idle_timeout,
+            &last_activity,
+        )
+        .await;
+        copied.map_err(|e| {
+            let side = side(&e, backend_stream_read.side());
+            CopyError::WriteFailure(e, side)
+        })
+    };
+    tokio::try_join!(read_request, write_response)
+}
+
+async fn relay<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    read: &mut R,
+    write: &mut W,
+    idle_timeout: Option<Duration>,
+    last_activity: &Mutex<Instant>,
+) -> Result<u64, std::io::Error> {
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut copied = 0;
+    loop {
+        let n = match idle_timeout {
+            Some(idle_timeout) => loop {
+                let deadline = *last_activity.lock().unwrap() + idle_timeout;
+                match tokio::time::timeout_at(deadline.into(), read.read(&mut buf)).await {
+                    Ok(n) => break n?,
+                    // the other direction may have been active in the meantime
+                    Err(_) if last_activity.lock().unwrap().elapsed() < idle_timeout => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            },
+            None => read.read(&mut buf).await?,
+        };
+        if n == 0 {
+            write.shutdown().await?;
+            return Ok(copied);
+        }
+        write.write_all(&buf[..n]).await?;
+        *last_activity.lock().unwrap() = Instant::now();
+        copied += n as u64;
+    }
+}
+
+// The PROXY protocol header goes first, in plaintext, before any TLS handshake with the backend.
+pub(crate) async fn connect_backend<B: ToSocketAddr>(
+    backend_address: &B,
+    remote_address: &SocketAddr,
+    local_address: Option<&SocketAddr>,
+    buffered: &[u8],
+) -> Result<BackendStream, std::io::Error> {
+    let mut stream = TcpStream::connect(backend_address.address()).await?;
+    if let Some(proxy_protocol) = backend_address.proxy_protocol() {
+        stream
+            .write_all(&proxy_protocol.header(remote_address, local_address)?)
+            .await?;
+    }
+    let mut backend_stream = match backend_address.tls() {
+        Some(tls) => BackendStream::Tls(Box::new(
+            tls.connect(stream, backend_address.address()).await?,
+        )),
+        None => BackendStream::Tcp(stream),
+    };
+    backend_stream.write_all(buffered).await?;
+    Ok(backend_stream)
+}
+
+pub(crate) enum BackendStream {
+    Tcp(TcpStream),
+    Tls(Box<client::TlsStream<TcpStream>>),
+}
+
+impl BackendStream {
+    async fn write_all(&mut self, buf: &[u8]) -> Result<(), std::io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.write_all(buf).await,
+            Self::Tls(stream) => stream.write_all(buf).await,
+        }
+    }
+}
+
+impl AsyncRead for BackendStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for BackendStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+pub(crate) enum CopyError {
+    ReadFailure(std::io::Error, Side),
+    WriteFailure(std::io::Error, Side),
+}
+
+/// The end of a proxied connection that made a copy fail.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Side {
+    Client,
+    Backend,
+}
+
+// Remembers whether the wrapped backend stream failed, so that a failed copy is blamed on the
+// right end.
+struct Watched<S> {
+    stream: S,
+    failed: bool,
+}
+
+impl<S> Watched<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream,
+            failed: false,
+        }
+    }
+    fn side(&self) -> Side {
+        if self.failed {
+            Side::Backend
+        } else {
+            Side::Client
+        }
+    }
+    fn watch<T>(&mut self, polled: Poll<std::io::Result<T>>) -> Poll<std::io::Result<T>> {
+        if let Poll::Ready(Err(_)) = polled {
+            self.failed = true;
+        }
+        polled
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Watched<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let polled = Pin::new(&mut this.stream).poll_read(cx, buf);
+        this.watch(polled)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Watched<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let polled = Pin::new(&mut this.stream).poll_write(cx, buf);
+        this.watch(polled)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let polled = Pin::new(&mut this.stream).poll_flush(cx);
+        this.watch(polled)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let polled = Pin::new(&mut this.stream).poll_shutdown(cx);
+        this.watch(polled)
+    }
 }
 
 impl BindAddress {
-    async fn bind(&self) -> Result<SocketListener, std::io::Error> {
+    pub(crate) async fn bind(&self) -> Result<SocketListener, std::io::Error> {
         match self {
             Self::TcpSocket(address) => TcpListener::bind(address)
                 .await
@@ -116,7 +531,7 @@ pub enum SocketListener {
 }
 
 impl SocketListener {
-    async fn accept(&self) -> Result<(AcceptStream, SocketAddr), std::io::Error> {
+    pub(crate) async fn accept(&self) -> Result<(AcceptStream, SocketAddr), std::io::Error> {
         match self {
             Self::Tcp(listener) => listener
                 .accept()
@@ -135,15 +550,28 @@ pub enum AcceptStream {
     #[cfg(target_os = "unix")]
     Unix(tokio::net::UnixStream),
     Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
 }
 
 impl AcceptStream {
+    fn local_addr(&self) -> Result<SocketAddr, std::io::Error> {
+        match self {
+            Self::Tcp(stream) => stream.local_addr(),
+            Self::Tls(stream) => stream.get_ref().0.local_addr(),
+            #[cfg(target_os = "unix")]
+            Self::Unix(_) => Err(std::io::Error::from(std::io::ErrorKind::Unsupported)),
+        }
+    }
     fn split(&mut self) -> (Read, Write) {
         match self {
             Self::Tcp(stream) => {
                 let (read, write) = stream.split();
                 (Read::Tcp(read), Write::Tcp(write))
             }
+            Self::Tls(stream) => {
+                let (read, write) = tokio::io::split(stream.as_mut());
+                (Read::Tls(read), Write::Tls(write))
+            }
             #[cfg(target_os = "unix")]
             Self::Unix(stream) => {
                 let (read, write) = stream.split();
@@ -153,31 +581,87 @@ impl AcceptStream {
     }
 }
 
+impl AsyncRead for AcceptStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(target_os = "unix")]
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AcceptStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(target_os = "unix")]
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(target_os = "unix")]
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(target_os = "unix")]
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
 pub enum Read<'a> {
     #[cfg(target_os = "unix")]
     Unix(tokio::net::unix::ReadHalf<'a>),
     Tcp(ReadHalf<'a>),
+    Tls(tokio::io::ReadHalf<&'a mut TlsStream<TcpStream>>),
 }
 
 pub enum Write<'a> {
     #[cfg(target_os = "unix")]
     Unix(tokio::net::unix::WriteHalf<'a>),
     Tcp(WriteHalf<'a>),
+    Tls(tokio::io::WriteHalf<&'a mut TlsStream<TcpStream>>),
 }
 
 impl<'a> Read<'a> {
-    async fn copy_to(&'a mut self, write: &'a mut WriteHalf<'a>) -> Result<u64, std::io::Error> {
+    async fn copy_to<W: AsyncWrite + Unpin>(
+        &mut self,
+        write: &mut W,
+    ) -> Result<u64, std::io::Error> {
         match self {
             Self::Tcp(read) => tokio::io::copy(read, write).await,
+            Self::Tls(read) => tokio::io::copy(read, write).await,
             #[cfg(target_os = "unix")]
             Self::Unix(read) => tokio::io::copy(read, write).await,
         }
     }
 }
 impl<'a> Write<'a> {
-    async fn copy_from(&'a mut self, read: &'a mut ReadHalf<'a>) -> Result<u64, std::io::Error> {
+    async fn copy_from<R: AsyncRead + Unpin>(
+        &mut self,
+        read: &mut R,
+    ) -> Result<u64, std::io::Error> {
         match self {
             Self::Tcp(write) => tokio::io::copy(read, write).await,
+            Self::Tls(write) => tokio::io::copy(read, write).await,
             #[cfg(target_os = "unix")]
             Self::Unix(write) => tokio::io::copy(read, write).await,
         }
@@ -0,0 +1,435 @@
+use crate::conf::{Conf, ToSocketAddr};
+use crate::errors::Error;
+use crate::sni::HostMap;
+use crossbeam::sync::ShardedLock;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+    SupportedProtocolVersion,
+};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::net::TcpStream;
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    fn supported(&self) -> &'static SupportedProtocolVersion {
+        match self {
+            Self::Tls12 => &rustls::version::TLS12,
+            Self::Tls13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+/// Reloads the certificates when their files change, and on SIGHUP. A reload that fails (e.g. a
+/// key that doesn't match its certificate yet because only one of them was replaced so far) keeps
+/// the current certificates.
+pub async fn reload_loop<B: ToSocketAddr + Sync + Send, C: Conf<B> + Sync>(
+    config: &'static C,
+) -> Result<(), Error> {
+    if config.certificate_paths().is_empty() {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    let mut modified = modification_times(config);
+    loop {
+        #[cfg(unix)]
+        let signal = hangup.recv();
+        #[cfg(not(unix))]
+        let signal = std::future::pending::<Option<()>>();
+        tokio::select! {
+            _ = signal => {},
+            _ = changed(config, &mut modified) => {},
+        }
+        match config.load_certificates() {
+            Ok(()) => println!("CERTIFICATES RELOADED"),
+            Err(e) => eprintln!("CERTIFICATE RELOAD FAILURE\n{}", e),
+        }
+    }
+}
+
+async fn changed<B: ToSocketAddr, C: Conf<B>>(
+    config: &C,
+    modified: &mut Vec<Option<(SystemTime, u64)>>,
+) {
+    match config.certificate_check_interval() {
+        Some(interval) => loop {
+            tokio::time::sleep(interval).await;
+            let current = modification_times(config);
+            if current != *modified {
+                *modified = current;
+                return;
+            }
+        },
+        None => std::future::pending().await,
+    }
+}
+
+fn modification_times<B: ToSocketAddr, C: Conf<B>>(config: &C) -> Vec<Option<(SystemTime, u64)>> {
+    config
+        .certificate_paths()
+        .iter()
+        .map(|it| {
+            std::fs::metadata(it)
+                .and_then(|it| Ok((it.modified()?, it.len())))
+                .ok()
+        })
+        .collect()
+}
+
+/// Certificate chain and private key PEM files, served to the clients asking for one of the
+/// server names. Names starting with "*." match any single label.
+#[derive(Clone, Debug)]
+pub struct TlsCertificate {
+    pub server_names: Vec<String>,
+    pub certificate_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TlsSettings {
+    /// The first certificate is also used for clients that don't send a matching server name.
+    pub certificates: Vec<TlsCertificate>,
+    /// All the versions supported by rustls when empty.
+    pub versions: Vec<TlsVersion>,
+    /// Cipher suite names (e.g. "TLS13_AES_256_GCM_SHA384"), all the rustls defaults when empty.
+    pub cipher_suites: Vec<String>,
+    /// Protocols offered with ALPN (e.g. "h2"), none when empty.
+    pub alpn_protocols: Vec<Vec<u8>>,
+}
+
+/// TLS termination for the frontend listener. The certificates are read from the files when
+/// loading, and the connections are accepted with the last configuration that loaded.
+pub struct Tls {
+    settings: TlsSettings,
+    acceptor: ShardedLock<Option<TlsAcceptor>>,
+}
+
+impl Tls {
+    pub fn new(settings: TlsSettings) -> Self {
+        Self {
+            settings,
+            acceptor: ShardedLock::new(None),
+        }
+    }
+    /// Reads the certificates and replaces the configuration used for new connections.
+    #[allow(dead_code)]
+    pub fn load(&self) -> Result<(), Error> {
+        let acceptor = self.prepare()?;
+        self.install(acceptor);
+        Ok(())
+    }
+    /// Reads the certificates, without using them yet.
+    pub(crate) fn prepare(&self) -> Result<TlsAcceptor, Error> {
+        Ok(TlsAcceptor::from(Arc::new(self.server_config()?)))
+    }
+    /// Handshakes that already started keep the previous configuration.
+    pub(crate) fn install(&self, acceptor: TlsAcceptor) {
+        *self.acceptor.write().unwrap() = Some(acceptor);
+    }
+    pub fn paths(&self) -> Vec<&Path> {
+        self.settings
+            .certificates
+            .iter()
+            .flat_map(|it| [it.certificate_path.as_path(), it.key_path.as_path()])
+            .collect()
+    }
+    pub(crate) async fn accept(
+        &self,
+        stream: TcpStream,
+    ) -> Result<server::TlsStream<TcpStream>, std::io::Error> {
+        let acceptor = self.acceptor.read().unwrap().clone();
+        match acceptor {
+            Some(acceptor) => acceptor.accept(stream).await,
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "certificates not loaded",
+            )),
+        }
+    }
+    fn server_config(&self) -> Result<ServerConfig, Error> {
+        let provider = self.provider()?;
+        let resolver = SniResolver::load(&self.settings.certificates, &provider)?;
+        let versions: Vec<&'static SupportedProtocolVersion> = if self.settings.versions.is_empty()
+        {
+            rustls::DEFAULT_VERSIONS.to_vec()
+        } else {
+            self.settings
+                .versions
+                .iter()
+                .map(|it| it.supported())
+                .collect()
+        };
+        let mut config = ServerConfig::builder_with_provider(Arc::new(provider))
+            .with_protocol_versions(&versions)
+            .map_err(|e| Error::InvalidTls(e.to_string()))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        config.alpn_protocols = self.settings.alpn_protocols.clone();
+        Ok(config)
+    }
+    fn provider(&self) -> Result<CryptoProvider, Error> {
+        let mut provider = rustls::crypto::ring::default_provider();
+        if !self.settings.cipher_suites.is_empty() {
+            let mut cipher_suites = Vec::with_capacity(self.settings.cipher_suites.len());
+            for name in &self.settings.cipher_suites {
+                match provider
+                    .cipher_suites
+                    .iter()
+                    .find(|it| format!("{:?}", it.suite()) == *name)
+                {
+                    Some(suite) => cipher_suites.push(*suite),
+                    None => {
+                        return Err(Error::InvalidTls(format!("unknown cipher suite: {}", name)))
+                    }
+                }
+            }
+            provider.cipher_suites = cipher_suites;
+        }
+        Ok(provider)
+    }
+}
+
+// Picks the certificate by the server name indication of the client hello.
+#[derive(Debug)]
+struct SniResolver {
+    certificates: HostMap<Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    fn load(certificates: &[TlsCertificate], provider: &CryptoProvider) -> Result<Self, Error> {
+        let mut resolver = Self {
+            certificates: HostMap::new(),
+            default: None,
+        };
+        for certificate in certificates {
+            let key = Arc::new(load_certified_key(certificate, provider)?);
+            for name in &certificate.server_names {
+                resolver.certificates.insert(name, key.clone());
+            }
+            resolver.default.get_or_insert(key);
+        }
+        Ok(resolver)
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|it| self.certificates.get(it).cloned())
+            .or_else(|| self.default.clone())
+    }
+}
+
+/// TLS toward the backends of a pool.
+#[derive(Clone, Debug)]
+pub struct BackendTlsSettings {
+    /// PEM bundle of the authorities that sign the backend certificates, needed to verify them.
+    pub ca_path: Option<PathBuf>,
+    /// Certificate chain and key PEM files presented to backends that require client
+    /// authentication.
+    pub client_certificate: Option<(PathBuf, PathBuf)>,
+    /// Name sent as SNI and checked against the backend certificates, the backend IP address by
+    /// default.
+    pub server_name: Option<String>,
+    pub verify: bool,
+}
+
+impl Default for BackendTlsSettings {
+    fn default() -> Self {
+        Self {
+            ca_path: None,
+            client_certificate: None,
+            server_name: None,
+            verify: true,
+        }
+    }
+}
+
+/// TLS client for the backend connections, with the last configuration that loaded.
+pub struct BackendTls {
+    settings: BackendTlsSettings,
+    alpn_protocols: Vec<Vec<u8>>,
+    connector: ShardedLock<Option<TlsConnector>>,
+}
+
+impl BackendTls {
+    pub fn new(settings: BackendTlsSettings) -> Self {
+        Self {
+            settings,
+            alpn_protocols: vec![],
+            connector: ShardedLock::new(None),
+        }
+    }
+    /// Protocols offered with ALPN, "h2" for HTTP/2 backends.
+    pub fn with_alpn_protocols(mut self, alpn_protocols: Vec<Vec<u8>>) -> Self {
+        self.alpn_protocols = alpn_protocols;
+        self
+    }
+    /// Reads the certificates and replaces the configuration used for new connections.
+    #[allow(dead_code)]
+    pub fn load(&self) -> Result<(), Error> {
+        let connector = self.prepare()?;
+        self.install(connector);
+        Ok(())
+    }
+    pub(crate) fn prepare(&self) -> Result<TlsConnector, Error> {
+        Ok(TlsConnector::from(Arc::new(self.client_config()?)))
+    }
+    pub(crate) fn install(&self, connector: TlsConnector) {
+        *self.connector.write().unwrap() = Some(connector);
+    }
+    pub fn paths(&self) -> Vec<&Path> {
+        let mut paths = vec![];
+        if let Some(ref ca_path) = self.settings.ca_path {
+            paths.push(ca_path.as_path());
+        }
+        if let Some((ref certificate_path, ref key_path)) = self.settings.client_certificate {
+            paths.push(certificate_path.as_path());
+            paths.push(key_path.as_path());
+        }
+        paths
+    }
+    pub(crate) async fn connect(
+        &self,
+        stream: TcpStream,
+        backend_address: &SocketAddr,
+    ) -> Result<client::TlsStream<TcpStream>, std::io::Error> {
+        let connector = self.connector.read().unwrap().clone();
+        let connector = connector.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotConnected, "certificates not loaded")
+        })?;
+        let server_name = match self.settings.server_name {
+            Some(ref name) => ServerName::try_from(name.clone())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+            None => ServerName::IpAddress(backend_address.ip().into()),
+        };
+        connector.connect(server_name, stream).await
+    }
+    fn client_config(&self) -> Result<ClientConfig, Error> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::InvalidTls(e.to_string()))?;
+        let builder = if self.settings.verify {
+            let ca_path = self.settings.ca_path.as_ref().ok_or_else(|| {
+                Error::InvalidTls("a CA bundle is needed to verify the backends".to_string())
+            })?;
+            let mut roots = RootCertStore::empty();
+            for certificate in load_certificates(ca_path)? {
+                roots
+                    .add(certificate)
+                    .map_err(|e| Error::InvalidTls(format!("{}: {}", ca_path.display(), e)))?;
+            }
+            builder.with_root_certificates(roots)
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+        };
+        let mut config = match self.settings.client_certificate {
+            Some((ref certificate_path, ref key_path)) => {
+                let chain = load_certificates(certificate_path)?;
+                let key =
+                    PrivateKeyDer::from_pem_file(key_path).map_err(|e| pem_error(key_path, e))?;
+                builder
+                    .with_client_auth_cert(chain, key)
+                    .map_err(|e| Error::InvalidTls(format!("{}: {}", key_path.display(), e)))?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.alpn_protocols.clone();
+        Ok(config)
+    }
+}
+
+// Accepts any backend certificate, the handshake signatures are still checked.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|it| it.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+    if certificates.is_empty() {
+        return Err(Error::InvalidTls(format!(
+            "{}: no certificate",
+            path.display()
+        )));
+    }
+    Ok(certificates)
+}
+
+fn load_certified_key(
+    certificate: &TlsCertificate,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, Error> {
+    let chain = load_certificates(&certificate.certificate_path)?;
+    let key = PrivateKeyDer::from_pem_file(&certificate.key_path)
+        .map_err(|e| pem_error(&certificate.key_path, e))?;
+    CertifiedKey::from_der(chain, key, provider)
+        .map_err(|e| Error::InvalidTls(format!("{}: {}", certificate.key_path.display(), e)))
+}
+
+fn pem_error(path: &Path, error: rustls::pki_types::pem::Error) -> Error {
+    Error::InvalidTls(format!("{}: {}", path.display(), error))
+}
@@ -1,13 +1,44 @@
+use headmaster::cache::{Cache, CacheSettings, Lookup};
+use headmaster::cidr::{Cidr, CidrTrie};
+use headmaster::compression::{CompressionSettings, Encoding};
 use headmaster::errors::Error;
+use headmaster::failure::{ErrorResponseSettings, Failure};
+use headmaster::forwarded::{ForwardedHeaders, Forwarding};
+use headmaster::headers::{HeaderAction, HeaderRule};
+use headmaster::pool::Unavailable;
+use headmaster::proxy::{read_header, ProxyProtocol, Tlv};
+use headmaster::retry::{Retries, RetrySettings};
+use headmaster::rewrite::{self, Outcome, UrlRule};
+use headmaster::route::{self, HttpRoute};
+use headmaster::sni::{peek_client_hello, ClientHello, HostMap};
+use headmaster::sticky::StickyCookie;
 use headmaster::tcp::*;
-use headmaster::{BindAddress, Conf, ConfBuilder, ConfImpl};
-use std::net::SocketAddr;
+use headmaster::tls::{reload_loop, BackendTlsSettings, TlsVersion};
+use headmaster::{
+    AccessOrder, AutoBan, BindAddress, Conf, ConfBuilder, ConfImpl, Metadata, OverloadPolicy,
+    Protocol, RateLimit, ToSocketAddr,
+};
+use http::{HeaderName, HeaderValue};
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use regex::Regex;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, ProtocolVersion, RootCertStore, ServerConfig};
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Runtime;
 use tokio::task::JoinHandle;
 use tokio::time::timeout;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
 #[macro_use]
 extern crate lazy_static;
@@ -57,6 +88,971 @@ fn test1() {
     balancer.abort();
 }
 
+#[test]
+fn test_cidr() {
+    let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+    assert_eq!(cidr.to_string(), "10.0.0.0/8");
+    assert!(cidr.contains(&"10.200.0.1".parse().unwrap()));
+    assert!(cidr.contains(&"::ffff:10.200.0.1".parse().unwrap()));
+    assert!(!cidr.contains(&"11.0.0.1".parse().unwrap()));
+    let mapped: Cidr = "::ffff:192.168.0.0/112".parse().unwrap();
+    assert_eq!(mapped.to_string(), "192.168.0.0/16");
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("2001:db8::/129".parse::<Cidr>().is_err());
+
+    let mut trie = CidrTrie::new();
+    trie.insert("10.0.0.0/8".parse().unwrap(), 8);
+    trie.insert("10.1.0.0/16".parse().unwrap(), 16);
+    trie.insert("2001:db8::/32".parse().unwrap(), 32);
+    let address: IpAddr = "10.1.2.3".parse().unwrap();
+    assert_eq!(trie.longest_match(&address).map(|it| *it.1), Some(16));
+    assert_eq!(trie.matches(&address).len(), 2);
+    assert_eq!(
+        trie.longest_match(&"2001:db8::1".parse().unwrap())
+            .map(|it| it.0.to_string()),
+        Some("2001:db8::/32".to_string())
+    );
+    assert_eq!(trie.remove(&"10.1.0.0/16".parse().unwrap()), Some(16));
+    assert_eq!(trie.longest_match(&address).map(|it| *it.1), Some(8));
+    assert_eq!(trie.len(), 2);
+}
+
+#[test]
+fn test_access_lists() {
+    let remote = |ip: &str| SocketAddr::new(ip.parse().unwrap(), 40000);
+    let conf = ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+        .deny("10.0.0.0/8".parse().unwrap())
+        .allow("10.1.2.3".parse().unwrap())
+        .build();
+    assert!(conf.accept(&remote("10.1.2.3")).is_none());
+    assert!(conf.accept(&remote("::ffff:10.9.9.9")).is_none());
+    assert!(conf.accept(&remote("192.168.0.1")).is_none());
+
+    let conf = ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+        .deny("10.0.0.0/8".parse().unwrap())
+        .allow("10.1.2.3".parse().unwrap())
+        .access_order(AccessOrder::AllowDeny)
+        .build();
+    assert!(conf.accept(&remote("10.1.2.3")).is_some());
+    assert!(conf.accept(&remote("10.9.9.9")).is_none());
+
+    let conf = ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+        .deny("2001:db8::/32".parse().unwrap())
+        .build();
+    assert!(conf.accept(&remote("2001:db8::1")).is_none());
+    assert!(conf.accept(&remote("192.168.0.1")).is_some());
+}
+
+#[test]
+fn test_deny_at_runtime() {
+    let remote = |ip: &str| SocketAddr::new(ip.parse().unwrap(), 40000);
+    let conf = ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+        .auto_ban(AutoBan {
+            window: Duration::from_secs(60),
+            max_connections: Some(2),
+            max_failures: Some(1),
+            ban_duration: Duration::from_secs(600),
+        })
+        .build();
+    conf.add_deny("10.0.0.0/8".parse().unwrap(), None);
+    conf.add_deny("172.16.0.0/12".parse().unwrap(), Some(Duration::ZERO));
+    assert!(conf.accept(&remote("10.1.2.3")).is_none());
+    assert!(conf.accept(&remote("172.16.0.1")).is_some());
+    assert_eq!(conf.denied().len(), 1);
+    conf.remove_deny("10.0.0.0/8".parse().unwrap());
+    assert!(conf.accept(&remote("10.1.2.3")).is_some());
+
+    assert!(conf.accept(&remote("192.168.0.1")).is_some());
+    assert!(conf.accept(&remote("192.168.0.1")).is_some());
+    assert!(conf.accept(&remote("192.168.0.1")).is_none());
+    assert!(conf.accept(&remote("192.168.0.2")).is_some());
+    let denied = conf.denied();
+    assert_eq!(denied.len(), 1);
+    assert_eq!(denied[0].0.to_string(), "192.168.0.1/32");
+    assert!(denied[0].1.unwrap() <= Duration::from_secs(600));
+    // a ban doesn't shorten the deny entries of the operator
+    conf.add_deny("192.168.0.3/32".parse().unwrap(), None);
+    conf.add_deny(
+        "192.168.0.4/32".parse().unwrap(),
+        Some(Duration::from_secs(3600)),
+    );
+    for ip in ["192.168.0.3", "192.168.0.4"] {
+        conf.record_client_failure(&remote(ip));
+        conf.record_client_failure(&remote(ip));
+    }
+    let denied = conf.denied();
+    let expiry = |cidr: &str| denied.iter().find(|it| it.0.to_string() == cidr).unwrap().1;
+    assert_eq!(expiry("192.168.0.3/32"), None);
+    assert!(expiry("192.168.0.4/32").unwrap() > Duration::from_secs(600));
+}
+
+#[test]
+fn test_auto_ban_client_failures() {
+    static TCP_CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    static HTTP_CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let auto_ban = AutoBan {
+        window: Duration::from_secs(60),
+        max_connections: None,
+        max_failures: Some(1),
+        ban_duration: Duration::from_secs(600),
+    };
+    let tcp_conf = TCP_CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .auto_ban(auto_ban)
+            .build()
+    });
+    let http_conf = HTTP_CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .auto_ban(auto_ban)
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        // resets every connection as soon as it is accepted
+        let listener = listen().await;
+        let backend = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                stream.set_zero_linger().unwrap();
+            }
+        });
+        let mut ports = vec![];
+        for conf in [tcp_conf, http_conf] {
+            conf.add_backend(backend);
+            let listener = bind(conf).await.unwrap();
+            ports.push(match listener {
+                SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+            });
+            tokio::spawn(accept_loop(conf, listener));
+        }
+        let proxy = |port: u16| SocketAddr::from(([127, 0, 0, 1], port));
+
+        for _ in 0..3 {
+            let mut stream = TcpStream::connect(proxy(ports[0])).await.unwrap();
+            let _ = stream.write_all(b"hello").await;
+            let _ = stream.read_to_end(&mut vec![]).await;
+
+            let mut sender = http_client(proxy(ports[1])).await;
+            let request = http::Request::builder()
+                .uri("/")
+                .header("host", "www.example.com")
+                .body(String::new())
+                .unwrap();
+            sender.ready().await.unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            assert_eq!(response.status(), 502);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(tcp_conf.denied().is_empty());
+        assert!(http_conf.denied().is_empty());
+
+        // invalid requests are the client's fault
+        for _ in 0..2 {
+            let mut stream = TcpStream::connect(proxy(ports[1])).await.unwrap();
+            stream.write_all(b"nonsense\r\n\r\n").await.unwrap();
+            let _ = stream.read_to_end(&mut vec![]).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let denied = http_conf.denied();
+        assert_eq!(denied.len(), 1);
+        assert_eq!(denied[0].0.to_string(), "127.0.0.1/32");
+    });
+}
+
+#[test]
+fn test_admin_access() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let remote = |ip: &str| SocketAddr::new(ip.parse().unwrap(), 40000);
+    let public = ConfBuilder::new(BindAddress::TcpSocket("0.0.0.0:80".parse().unwrap())).build();
+    match public.admin_address() {
+        BindAddress::TcpSocket(address) => assert_eq!(address.to_string(), "127.0.0.1:8000"),
+        #[allow(unreachable_patterns)]
+        _ => panic!("unexpected admin address"),
+    }
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .admin_address(BindAddress::TcpSocket(*ADDRESS))
+            .admin_source("10.0.0.0/8".parse().unwrap())
+            .build()
+    });
+    assert!(conf.accepts_admin(&remote("127.0.0.1")));
+    assert!(conf.accepts_admin(&remote("::1")));
+    assert!(conf.accepts_admin(&remote("10.1.2.3")));
+    assert!(conf.accepts_admin(&remote("::ffff:127.0.0.1")));
+    assert!(conf.accepts_admin(&remote("::ffff:10.1.2.3")));
+    assert!(!conf.accepts_admin(&remote("192.168.0.1")));
+
+    let runtime = runtime(1);
+    runtime.block_on(async move {
+        let listener = headmaster::admin::bind(conf).await.unwrap();
+        let address = match &listener {
+            SocketListener::Tcp(listener) => listener.local_addr().unwrap(),
+            #[allow(unreachable_patterns)]
+            _ => panic!("unexpected admin listener"),
+        };
+        tokio::spawn(headmaster::admin::admin_loop(conf, listener));
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"deny list\n").await.unwrap();
+        let mut buf = [0; 64];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"OK\n");
+
+        // an endless line is cut off instead of being buffered
+        stream.write_all(&[b'a'; 10_000]).await.unwrap();
+        // the unread rest of the line may reset the connection after the answer
+        let n = timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], b"ERROR line too long\n");
+    });
+}
+
+#[test]
+fn test_max_connections_per_client() {
+    let remote = |ip: &str| SocketAddr::new(ip.parse().unwrap(), 40000);
+    let conf = ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+        .max_connections_per_client(2)
+        .client_limit_exemption("10.0.0.0/8".parse().unwrap())
+        .build();
+    let first = conf.accept(&remote("192.168.0.1")).unwrap();
+    assert!(conf.accept(&remote("::ffff:192.168.0.1")).is_some());
+    assert!(conf.accept(&remote("192.168.0.1")).is_none());
+    assert!(conf.accept(&remote("192.168.0.2")).is_some());
+    conf.release(&remote("192.168.0.1"), first);
+    assert!(conf.accept(&remote("192.168.0.1")).is_some());
+    for _ in 0..10 {
+        assert!(conf.accept(&remote("10.1.2.3")).is_some());
+    }
+}
+
+#[test]
+fn test_rate_limit() {
+    let remote = |ip: &str| SocketAddr::new(ip.parse().unwrap(), 40000);
+    let conf = ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+        .rate_limit_per_client(RateLimit {
+            rate: 0.001,
+            burst: 2,
+        })
+        .rate_limit_per_subnet(RateLimit {
+            rate: 0.001,
+            burst: 3,
+        })
+        .build();
+    assert!(conf.accept(&remote("192.168.0.1")).is_some());
+    assert!(conf.accept(&remote("192.168.0.1")).is_some());
+    assert!(conf.accept(&remote("192.168.0.1")).is_none());
+    assert!(conf.accept(&remote("192.168.0.2")).is_some());
+    assert!(conf.accept(&remote("192.168.0.3")).is_none());
+    assert!(conf.accept(&remote("192.168.1.1")).is_some());
+    assert!(conf.accept(&remote("2001:db8::1")).is_some());
+    assert!(conf.accept(&remote("2001:db8::2")).is_some());
+    assert!(conf.accept(&remote("2001:db8::3")).is_some());
+    assert!(conf.accept(&remote("2001:db8::4")).is_none());
+
+    // connections rejected by the session cap don't spend any rate
+    let capped = ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+        .max_connections_per_client(1)
+        .rate_limit_per_client(RateLimit {
+            rate: 0.001,
+            burst: 2,
+        })
+        .rate_limit_per_subnet(RateLimit {
+            rate: 0.001,
+            burst: 3,
+        })
+        .build();
+    let first = capped.accept(&remote("192.168.0.1")).unwrap();
+    for _ in 0..5 {
+        assert!(capped.accept(&remote("192.168.0.1")).is_none());
+    }
+    capped.release(&remote("192.168.0.1"), first);
+    let second = capped.accept(&remote("192.168.0.1")).unwrap();
+    capped.release(&remote("192.168.0.1"), second);
+    assert!(capped.accept(&remote("192.168.0.1")).is_none());
+    assert!(capped.accept(&remote("192.168.0.2")).is_some());
+
+    let unlimited = ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+        .rate_limit_per_client(RateLimit {
+            rate: 0.0,
+            burst: 2,
+        })
+        .rate_limit_per_subnet(RateLimit {
+            rate: 0.001,
+            burst: 0,
+        })
+        .build();
+    for _ in 0..5 {
+        assert!(unlimited.accept(&remote("192.168.0.1")).is_some());
+    }
+}
+
+#[test]
+fn test_session_limit() {
+    let conf = ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+        .max_sessions(2)
+        .overload_policy(OverloadPolicy::Close)
+        .build();
+    let limit = conf.session_limit();
+    assert_eq!(limit.policy(), OverloadPolicy::Close);
+    let first = limit.try_acquire().unwrap();
+    let second = limit.try_acquire().unwrap();
+    assert_eq!(limit.active_sessions(), 2);
+    assert!(limit.try_acquire().is_none());
+    drop(first);
+    assert_eq!(limit.active_sessions(), 1);
+    assert!(limit.try_acquire().is_some());
+    drop(second);
+    assert_eq!(limit.active_sessions(), 0);
+}
+
+#[test]
+fn test_backend_queue() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .max_connections_per_backend(1)
+            .max_queue_length(1)
+            .queue_timeout(Duration::from_millis(200))
+            .build()
+    });
+    let remote = SocketAddr::from(([192, 168, 0, 1], 40000));
+    let backend_address = SocketAddr::from(([127, 0, 0, 1], 1));
+    conf.add_backend(backend_address);
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        let trace = conf.accept(&remote).unwrap();
+        let first = conf
+            .select(&remote, Metadata::default(), trace)
+            .await
+            .unwrap();
+        assert_eq!(*first.address(), backend_address);
+        let waiting =
+            tokio::spawn(async move { conf.select(&remote, Metadata::default(), trace).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            conf.select(&remote, Metadata::default(), trace).await.err(),
+            Some(Unavailable::QueueFull)
+        );
+        conf.record_success(&remote, first, 0, 0, trace);
+        let second = waiting.await.unwrap().unwrap();
+        assert!(
+            timeout(
+                Duration::from_millis(500),
+                conf.select(&remote, Metadata::default(), trace)
+            )
+            .await
+            .unwrap()
+            .err()
+                == Some(Unavailable::QueueTimeout)
+        );
+        conf.set_backend_max_connections(backend_address, Some(2));
+        assert!(conf
+            .select(&remote, Metadata::default(), trace)
+            .await
+            .is_ok());
+        conf.record_success(&remote, second, 0, 0, trace);
+    });
+}
+
+#[test]
+fn test_no_backend_grace_period() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .no_backend_grace_period(Duration::from_millis(200))
+            .build()
+    });
+    let remote = SocketAddr::from(([192, 168, 0, 1], 40000));
+    let backend_address = SocketAddr::from(([127, 0, 0, 1], 1));
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        let trace = conf.accept(&remote).unwrap();
+        assert_eq!(
+            conf.select(&remote, Metadata::default(), trace).await.err(),
+            Some(Unavailable::NoBackend)
+        );
+        let waiting =
+            tokio::spawn(async move { conf.select(&remote, Metadata::default(), trace).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        conf.add_backend(backend_address);
+        let backend = waiting.await.unwrap().unwrap();
+        assert_eq!(*backend.address(), backend_address);
+        conf.record_success(&remote, backend, 0, 0, trace);
+
+        conf.set_backend_available(backend_address, false);
+        let waiting =
+            tokio::spawn(async move { conf.select(&remote, Metadata::default(), trace).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        conf.set_backend_available(backend_address, true);
+        assert!(waiting.await.unwrap().is_ok());
+    });
+}
+
+#[test]
+fn test_proxy_protocol_header() {
+    let source = SocketAddr::from(([192, 168, 0, 1], 40000));
+    let destination = SocketAddr::from(([10, 0, 0, 1], 443));
+    assert_eq!(
+        ProxyProtocol::V1
+            .header(&source, Some(&destination))
+            .unwrap(),
+        b"PROXY TCP4 192.168.0.1 10.0.0.1 40000 443\r\n"
+    );
+    let mixed = SocketAddr::new("2001:db8::1".parse().unwrap(), 443);
+    assert_eq!(
+        ProxyProtocol::V1.header(&source, Some(&mixed)).unwrap(),
+        b"PROXY TCP6 ::ffff:192.168.0.1 2001:db8::1 40000 443\r\n"
+    );
+    assert_eq!(
+        ProxyProtocol::V1.header(&source, None).unwrap(),
+        b"PROXY UNKNOWN\r\n"
+    );
+    let v2 = ProxyProtocol::V2 {
+        tlvs: vec![Tlv {
+            kind: 0x02,
+            value: b"example.com".to_vec(),
+        }],
+    };
+    let header = v2.header(&source, Some(&destination)).unwrap();
+    assert_eq!(&header[0..12], b"\r\n\r\n\0\r\nQUIT\n");
+    assert_eq!(header[12], 0x21);
+    assert_eq!(header[13], 0x11);
+    assert_eq!(u16::from_be_bytes([header[14], header[15]]), 12 + 3 + 11);
+    assert_eq!(&header[16..20], &[192, 168, 0, 1]);
+    assert_eq!(&header[20..24], &[10, 0, 0, 1]);
+    assert_eq!(&header[24..28], &[0x9c, 0x40, 0x01, 0xbb]);
+    assert_eq!(&header[28..31], &[0x02, 0, 11]);
+    assert_eq!(&header[31..], b"example.com");
+    // lengths that don't fit in 16 bits are rejected instead of being truncated
+    let tlv = |length: usize| Tlv {
+        kind: 0xe0,
+        value: vec![0; length],
+    };
+    for tlvs in [vec![tlv(65536)], vec![tlv(30000), tlv(30000), tlv(30000)]] {
+        let v2 = ProxyProtocol::V2 { tlvs };
+        assert!(v2.header(&source, Some(&destination)).is_err());
+    }
+}
+
+#[test]
+fn test_proxy_protocol_to_backend() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .proxy_protocol(ProxyProtocol::V1)
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        let backend = listen().await;
+        conf.add_backend(backend.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut received = vec![];
+            let mut buf = vec![0u8; 1024];
+            while !received.ends_with(b"data") {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0);
+                received.extend_from_slice(&buf[0..n]);
+            }
+            stream.write_all(&received).await.unwrap();
+        });
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let mut stream = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port)))
+            .await
+            .unwrap();
+        let client_port = stream.local_addr().unwrap().port();
+        stream.write_all(b"data").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert_eq!(
+            response,
+            format!(
+                "PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\ndata",
+                client_port, port
+            )
+        );
+    });
+}
+
+#[test]
+fn test_pool_proxy_protocol() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .server_name_route("a.example.com", "a")
+            .server_name_route("b.example.com", "b")
+            .pool_proxy_protocol("b", ProxyProtocol::V1)
+            .build()
+    });
+    let remote = SocketAddr::from(([192, 168, 0, 1], 40000));
+    conf.add_backend(SocketAddr::from(([127, 0, 0, 1], 1)));
+    assert!(conf.add_pool_backend("a", SocketAddr::from(([127, 0, 0, 1], 2))));
+    assert!(conf.add_pool_backend("b", SocketAddr::from(([127, 0, 0, 1], 3))));
+    let runtime = runtime(1);
+    runtime.block_on(async move {
+        for (server_name, expected) in [
+            (None, None),
+            (Some("a"), None),
+            (Some("b"), Some(ProxyProtocol::V1)),
+        ] {
+            let client_hello = ClientHello {
+                server_name: server_name.map(|it| format!("{}.example.com", it)),
+                alpn_protocols: vec![],
+            };
+            let metadata = Metadata {
+                client_hello: Some(&client_hello),
+                request: None,
+            };
+            let trace = conf.accept(&remote).unwrap();
+            let backend = conf.select(&remote, metadata, trace).await.unwrap();
+            assert_eq!(backend.proxy_protocol(), expected.as_ref());
+            conf.record_success(&remote, backend, 0, 0, trace);
+            conf.release(&remote, trace);
+        }
+    });
+}
+
+#[test]
+fn test_read_proxy_header() {
+    let runtime = runtime(1);
+    runtime.block_on(async {
+        let source = SocketAddr::from(([192, 0, 2, 1], 1234));
+        let destination = SocketAddr::from(([198, 51, 100, 1], 443));
+        let tlv = Tlv {
+            kind: 0x04,
+            value: b"id".to_vec(),
+        };
+        for protocol in [ProxyProtocol::V1, ProxyProtocol::V2 { tlvs: vec![tlv] }] {
+            let mut bytes = protocol.header(&source, Some(&destination)).unwrap();
+            bytes.extend_from_slice(b"data");
+            let mut stream = bytes.as_slice();
+            let header = read_header(&mut stream).await.unwrap();
+            assert_eq!(header.source, Some(source));
+            assert_eq!(header.destination, Some(destination));
+            assert_eq!(stream, b"data");
+        }
+        let header = read_header(&mut &b"PROXY UNKNOWN\r\n"[..]).await.unwrap();
+        assert_eq!(header.source, None);
+        assert!(read_header(&mut &b"GET / HTTP/1.1\r\n"[..]).await.is_err());
+        assert!(read_header(&mut &b"PROXY TCP4 1.2.3.4\r\n"[..])
+            .await
+            .is_err());
+        // addresses of the other family than the protocol
+        for line in [
+            &b"PROXY TCP4 ::1 ::1 1 2\r\n"[..],
+            b"PROXY TCP4 1.2.3.4 ::1 1 2\r\n",
+            b"PROXY TCP6 1.2.3.4 5.6.7.8 1 2\r\n",
+        ] {
+            assert!(read_header(&mut &line[..]).await.is_err());
+        }
+        let header = read_header(&mut &b"PROXY TCP6 ::1 ::1 1 2\r\n"[..])
+            .await
+            .unwrap();
+        assert_eq!(header.source, Some("[::1]:1".parse().unwrap()));
+        let v2 = |family: u8, addresses: usize| {
+            let mut bytes = b"\r\n\r\n\0\r\nQUIT\n\x21".to_vec();
+            bytes.push(family);
+            bytes.extend_from_slice(&(addresses as u16).to_be_bytes());
+            bytes.extend_from_slice(&vec![0; addresses]);
+            bytes
+        };
+        // AF_UNSPEC and AF_UNIX: no client address, the peer address is kept
+        for (family, length) in [(0x00, 0), (0x31, 216), (0x32, 216)] {
+            let header = read_header(&mut v2(family, length).as_slice())
+                .await
+                .unwrap();
+            assert_eq!(header.source, None);
+            assert_eq!(header.destination, None);
+        }
+        // unknown transport
+        assert!(read_header(&mut v2(0x13, 12).as_slice()).await.is_err());
+        assert!(read_header(&mut v2(0x03, 0).as_slice()).await.is_err());
+        assert!(read_header(&mut v2(0x41, 0).as_slice()).await.is_err());
+    });
+}
+
+#[test]
+fn test_proxy_protocol_from_balancer() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .accept_proxy_protocol("127.0.0.0/8".parse().unwrap())
+            .deny("192.0.2.2".parse().unwrap())
+            .proxy_protocol(ProxyProtocol::V1)
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        let backend = listen().await;
+        conf.add_backend(backend.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut received = vec![];
+            let mut buf = vec![0u8; 1024];
+            while !received.ends_with(b"data") {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0);
+                received.extend_from_slice(&buf[0..n]);
+            }
+            stream.write_all(&received).await.unwrap();
+        });
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let address = SocketAddr::from(([127, 0, 0, 1], port));
+        // the forwarded client address is the one that is checked against the deny list
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"PROXY TCP4 192.0.2.2 198.51.100.1 1234 443\r\ndata")
+            .await
+            .unwrap();
+        // closed without reading the request, which can end with a reset
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        assert_eq!(response, "");
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"PROXY TCP4 192.0.2.1 198.51.100.1 1234 443\r\ndata")
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert_eq!(
+            response,
+            "PROXY TCP4 192.0.2.1 198.51.100.1 1234 443\r\ndata"
+        );
+    });
+}
+
+#[test]
+fn test_tls_termination() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .tls_certificate(
+                &["localhost"],
+                cert_path("localhost.pem"),
+                cert_path("localhost.key"),
+            )
+            .tls_certificate(
+                &["example.com", "*.example.com"],
+                cert_path("example.pem"),
+                cert_path("example.key"),
+            )
+            .tls_versions(&[TlsVersion::Tls13])
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        let backend = listen().await;
+        conf.add_backend(backend.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = backend.accept().await {
+                tokio::spawn(async move {
+                    let (mut read, mut write) = stream.split();
+                    tokio::io::copy(&mut read, &mut write).await.unwrap();
+                });
+            }
+        });
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let address = SocketAddr::from(([127, 0, 0, 1], port));
+        for (server_name, certificate) in [
+            ("localhost", "localhost.pem"),
+            ("www.example.com", "example.pem"),
+        ] {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let connector = tls_connector();
+            let mut stream = connector
+                .connect(ServerName::try_from(server_name).unwrap(), stream)
+                .await
+                .unwrap();
+            let (_, connection) = stream.get_ref();
+            assert_eq!(
+                connection.peer_certificates().unwrap()[0],
+                CertificateDer::from_pem_file(cert_path(certificate)).unwrap()
+            );
+            assert_eq!(
+                connection.protocol_version(),
+                Some(ProtocolVersion::TLSv1_3)
+            );
+            stream.write_all(b"data").await.unwrap();
+            let mut response = [0u8; 4];
+            stream.read_exact(&mut response).await.unwrap();
+            assert_eq!(&response, b"data");
+        }
+        // the wildcard only matches a single label, the default certificate is served instead
+        let stream = TcpStream::connect(address).await.unwrap();
+        let result = tls_connector()
+            .connect(ServerName::try_from("a.b.example.com").unwrap(), stream)
+            .await;
+        assert!(result.is_err());
+    });
+}
+
+#[test]
+fn test_tls_invalid_settings() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .tls_certificate(
+                &["localhost"],
+                cert_path("localhost.pem"),
+                cert_path("example.key"),
+            )
+            .build()
+    });
+    let runtime = runtime(1);
+    runtime.block_on(async move {
+        assert!(matches!(bind(conf).await, Err(Error::InvalidTls(_))));
+    });
+    let conf = ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+        .tls_certificate(
+            &["localhost"],
+            cert_path("localhost.pem"),
+            cert_path("localhost.key"),
+        )
+        .tls_cipher_suites(&["TLS13_AES_256_GCM_SHA384", "TLS_UNKNOWN"])
+        .build();
+    assert!(matches!(
+        conf.tls().unwrap().load(),
+        Err(Error::InvalidTls(_))
+    ));
+}
+
+#[test]
+fn test_peek_client_hello() {
+    let runtime = runtime(1);
+    runtime.block_on(async {
+        let bytes = client_hello("www.Example.com", &[b"h2", b"http/1.1"]);
+        let mut stream = bytes.as_slice();
+        let (client_hello, buffered) = peek_client_hello(&mut stream).await.unwrap();
+        let client_hello = client_hello.unwrap();
+        assert_eq!(client_hello.server_name.as_deref(), Some("www.example.com"));
+        assert_eq!(
+            client_hello.alpn_protocols,
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+        assert_eq!(buffered, bytes);
+        let mut stream = &b"GET / HTTP/1.1\r\n"[..];
+        let (client_hello, buffered) = peek_client_hello(&mut stream).await.unwrap();
+        assert_eq!(client_hello, None);
+        assert_eq!(buffered, b"GET /");
+    });
+    let mut routes = HostMap::new();
+    routes.insert("example.com", 1);
+    routes.insert("*.example.com", 2);
+    routes.insert("www.example.com", 3);
+    assert_eq!(routes.get("example.com"), Some(&1));
+    assert_eq!(routes.get("api.EXAMPLE.com"), Some(&2));
+    assert_eq!(routes.get("www.example.com"), Some(&3));
+    assert_eq!(routes.get("a.b.example.com"), None);
+}
+
+#[test]
+fn test_server_name_routing() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .server_name_route("*.example.com", "example")
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        for (pool, id) in [(None, b'1'), (Some("example"), b'2')] {
+            let backend = listen().await;
+            match pool {
+                Some(pool) => assert!(conf.add_pool_backend(pool, backend.local_addr().unwrap())),
+                None => conf.add_backend(backend.local_addr().unwrap()),
+            }
+            tokio::spawn(async move {
+                while let Ok((mut stream, _)) = backend.accept().await {
+                    let mut header = [0u8; 5];
+                    stream.read_exact(&mut header).await.unwrap();
+                    let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+                    let mut record = vec![0u8; length];
+                    stream.read_exact(&mut record).await.unwrap();
+                    stream.write_all(&[id]).await.unwrap();
+                }
+            });
+        }
+        assert!(!conf.add_pool_backend("unknown", *ADDRESS));
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let address = SocketAddr::from(([127, 0, 0, 1], port));
+        for (server_name, id) in [
+            ("www.example.com", b'2'),
+            ("example.com", b'1'),
+            ("localhost", b'1'),
+        ] {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream
+                .write_all(&client_hello(server_name, &[]))
+                .await
+                .unwrap();
+            assert_eq!(stream.read_u8().await.unwrap(), id);
+        }
+    });
+}
+
+#[test]
+fn test_backend_mutual_tls() {
+    static VERIFIED: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    static UNVERIFIED: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let verified = VERIFIED.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .backend_tls(BackendTlsSettings {
+                ca_path: Some(cert_path("ca.pem").into()),
+                client_certificate: Some((
+                    cert_path("client.pem").into(),
+                    cert_path("client.key").into(),
+                )),
+                server_name: Some("localhost".to_string()),
+                verify: true,
+            })
+            .build()
+    });
+    // the backend certificate is not valid for its ip address, but it isn't verified
+    let unverified = UNVERIFIED.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .backend_tls(BackendTlsSettings {
+                client_certificate: Some((
+                    cert_path("client.pem").into(),
+                    cert_path("client.key").into(),
+                )),
+                verify: false,
+                ..Default::default()
+            })
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(cert_path("ca.pem")).unwrap())
+            .unwrap();
+        let client_verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+            .build()
+            .unwrap();
+        let config = ServerConfig::builder()
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(
+                vec![CertificateDer::from_pem_file(cert_path("localhost.pem")).unwrap()],
+                PrivateKeyDer::from_pem_file(cert_path("localhost.key")).unwrap(),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let backend = listen().await;
+        let backend_address = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = backend.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = acceptor.accept(stream).await.unwrap();
+                    assert!(stream.get_ref().1.peer_certificates().is_some());
+                    let mut request = [0u8; 4];
+                    stream.read_exact(&mut request).await.unwrap();
+                    stream.write_all(&request).await.unwrap();
+                    stream.shutdown().await.unwrap();
+                });
+            }
+        });
+        for conf in [verified, unverified] {
+            conf.add_backend(backend_address);
+            let listener = bind(conf).await.unwrap();
+            let port = match listener {
+                SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+            };
+            tokio::spawn(accept_loop(conf, listener));
+            let mut stream = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port)))
+                .await
+                .unwrap();
+            stream.write_all(b"data").await.unwrap();
+            let mut response = [0u8; 4];
+            stream.read_exact(&mut response).await.unwrap();
+            assert_eq!(&response, b"data");
+        }
+    });
+    let conf = ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+        .backend_tls(BackendTlsSettings::default())
+        .build();
+    assert!(matches!(
+        conf.load_certificates(),
+        Err(Error::InvalidTls(_))
+    ));
+}
+
+#[test]
+fn test_certificate_reload() {
+    let directory = std::env::temp_dir().join(format!("headmaster-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let certificate_path = directory.join("certificate.pem");
+    let key_path = directory.join("key.pem");
+    let install = |certificate: &str, key: &str| {
+        std::fs::copy(cert_path(key), &key_path).unwrap();
+        std::fs::copy(cert_path(certificate), &certificate_path).unwrap();
+    };
+    install("localhost.pem", "localhost.key");
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .tls_certificate(&["localhost", "example.com"], &certificate_path, &key_path)
+            .certificate_check_interval(Duration::from_millis(50))
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        let backend = listen().await;
+        conf.add_backend(backend.local_addr().unwrap());
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        tokio::spawn(reload_loop(conf));
+        let address = SocketAddr::from(([127, 0, 0, 1], port));
+        let peer_certificate = |server_name: &'static str| async move {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let stream = tls_connector()
+                .connect(ServerName::try_from(server_name).unwrap(), stream)
+                .await
+                .unwrap();
+            stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+        };
+        let localhost = CertificateDer::from_pem_file(cert_path("localhost.pem")).unwrap();
+        let example = CertificateDer::from_pem_file(cert_path("example.pem")).unwrap();
+        assert_eq!(peer_certificate("localhost").await, localhost);
+        // a key that doesn't match the certificate is rejected, the previous ones stay in use
+        install("localhost.pem", "example.key");
+        assert!(matches!(
+            conf.load_certificates(),
+            Err(Error::InvalidTls(_))
+        ));
+        assert_eq!(peer_certificate("localhost").await, localhost);
+        install("example.pem", "example.key");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(peer_certificate("example.com").await, example);
+    });
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
 async fn request(port: u16) -> Result<u8, Error> {
     let address = SocketAddr::from(([127, 0, 0, 1], port));
     let mut stream = TcpStream::connect(&address).await?;
@@ -75,6 +1071,1524 @@ fn start_backend(thread_count: usize, id: &'static [u8]) -> (SocketAddr, JoinHan
     (address, backend)
 }
 
+#[test]
+fn test_http_route_matches() {
+    let request = |uri: &str, host: Option<&str>| {
+        let mut builder = http::Request::builder().uri(uri);
+        if let Some(host) = host {
+            builder = builder.header("host", host);
+        }
+        builder.body(()).unwrap().into_parts().0
+    };
+    assert_eq!(
+        route::host(&request("/", Some("WWW.Example.com:8080"))),
+        Some("www.example.com".to_string())
+    );
+    assert_eq!(
+        route::host(&request("/", Some("[::1]"))),
+        Some("[::1]".to_string())
+    );
+    assert_eq!(
+        route::host(&request("http://example.com/", Some("other.com"))),
+        Some("example.com".to_string())
+    );
+    assert_eq!(route::host(&request("/", None)), None);
+    let route = HttpRoute::to_pool("example").host("*.example.com");
+    assert!(route.matches(&request("/", Some("www.example.com"))));
+    assert!(!route.matches(&request("/", Some("example.com"))));
+    assert!(!route.matches(&request("/", Some("a.www.example.com"))));
+    assert!(!route.matches(&request("/", None)));
+    let route = HttpRoute::to_pool("api")
+        .host("example.com")
+        .path_prefix("/api/");
+    assert!(route.matches(&request("/api/users", Some("example.com"))));
+    assert!(!route.matches(&request("/apiv2", Some("example.com"))));
+    assert!(!route.matches(&request("/api/users", Some("www.example.com"))));
+    let route = HttpRoute::to_pool("images").path_regex(Regex::new(r"\.(png|jpg)$").unwrap());
+    assert!(route.matches(&request("/a/b.png", None)));
+    assert!(!route.matches(&request("/a/b.png/c", None)));
+}
+
+#[test]
+fn test_http_routing() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .http_route(HttpRoute::to_pool("api").host("api.example.com"))
+            .http_route(HttpRoute::to_pool("static").path_prefix("/static/"))
+            .http_route(HttpRoute::to_pool("images").path_regex(Regex::new(r"\.png$").unwrap()))
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        let mut backends = vec![];
+        for pool in [None, Some("api"), Some("static"), Some("images")] {
+            let (backend, accepted) = http_backend(pool.unwrap_or("default")).await;
+            match pool {
+                Some(pool) => assert!(conf.add_pool_backend(pool, backend)),
+                None => conf.add_backend(backend),
+            }
+            backends.push((backend, accepted));
+        }
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let mut sender = http_client(SocketAddr::from(([127, 0, 0, 1], port))).await;
+        // all the requests go through the same client connection
+        for (host, path, expected) in [
+            ("api.example.com", "/static/a.png", "api /static/a.png"),
+            ("www.example.com", "/static/a.css", "static /static/a.css"),
+            ("www.example.com", "/a.png", "images /a.png"),
+            ("www.example.com", "/", "default /"),
+            ("www.example.com", "/index.html", "default /index.html"),
+        ] {
+            let request = http::Request::builder()
+                .uri(path)
+                .header("host", host)
+                .body(String::new())
+                .unwrap();
+            sender.ready().await.unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            assert_eq!(response.status(), 200);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, expected.as_bytes());
+        }
+        // the connection to the default backend was reused for the second request
+        assert_eq!(backends[0].1.load(Ordering::SeqCst), 1);
+        let request = http::Request::builder()
+            .uri("/")
+            .header("host", "www.example.com")
+            .body(String::new())
+            .unwrap();
+        conf.remove_backend(backends[0].0);
+        sender.ready().await.unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), 503);
+    });
+}
+
+#[test]
+fn test_http_per_request_balancing() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        let mut backends = vec![];
+        for name in ["first", "second"] {
+            let (backend, accepted) = http_backend(name).await;
+            conf.add_backend(backend);
+            backends.push((backend, accepted));
+        }
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let address = SocketAddr::from(([127, 0, 0, 1], port));
+        let get = |sender: &mut hyper::client::conn::http1::SendRequest<String>| {
+            let request = http::Request::builder()
+                .uri("/")
+                .header("host", "localhost")
+                .body(String::new())
+                .unwrap();
+            let response = sender.send_request(request);
+            async move {
+                let body = response.await.unwrap().into_body().collect().await;
+                String::from_utf8(body.unwrap().to_bytes().to_vec()).unwrap()
+            }
+        };
+        let mut client = http_client(address).await;
+        assert_eq!(get(&mut client).await, "first /");
+        // the next request of the same client connection goes elsewhere
+        conf.set_backend_available(backends[0].0, false);
+        client.ready().await.unwrap();
+        assert_eq!(get(&mut client).await, "second /");
+        // another client reuses the connection to the backend
+        let mut other_client = http_client(address).await;
+        assert_eq!(get(&mut other_client).await, "second /");
+        conf.set_backend_available(backends[0].0, true);
+        other_client.ready().await.unwrap();
+        assert_eq!(get(&mut other_client).await, "first /");
+        assert_eq!(backends[0].1.load(Ordering::SeqCst), 1);
+        assert_eq!(backends[1].1.load(Ordering::SeqCst), 1);
+        // HTTP/2 with prior knowledge, to the same HTTP/1.1 backends
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let request = http::Request::builder()
+            .uri("http://localhost/h2c")
+            .body(String::new())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.version(), http::Version::HTTP_2);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "first /h2c");
+    });
+}
+
+#[test]
+fn test_http_proxy_protocol_to_backend() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .proxy_protocol(ProxyProtocol::V1)
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        // answers with the client address of the PROXY protocol header of the connection
+        let listener = listen().await;
+        conf.add_backend(listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let source = read_header(&mut stream).await.unwrap().source.unwrap();
+                    let service = service_fn(move |_: http::Request<Incoming>| async move {
+                        Ok::<_, std::convert::Infallible>(http::Response::new(source.to_string()))
+                    });
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let address = SocketAddr::from(([127, 0, 0, 1], port));
+        // the connection opened for the first client isn't reused for the second one
+        for _ in 0..2 {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let client_address = stream.local_addr().unwrap();
+            let (mut sender, connection) =
+                hyper::client::conn::http1::handshake(TokioIo::new(stream))
+                    .await
+                    .unwrap();
+            tokio::spawn(connection);
+            let request = http::Request::builder()
+                .uri("/")
+                .header("host", "localhost")
+                .body(String::new())
+                .unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, client_address.to_string().as_bytes());
+        }
+    });
+}
+
+#[test]
+fn test_forwarded_headers() {
+    let both = ForwardedHeaders {
+        x_forwarded: true,
+        forwarded: true,
+    };
+    let remote_address = SocketAddr::from(([192, 168, 0, 1], 4000));
+    let local_address = SocketAddr::from(([10, 0, 0, 1], 443));
+    let forwarding = Forwarding {
+        remote_address: &remote_address,
+        local_address: Some(&local_address),
+        tls: true,
+    };
+    let incoming = || {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("host", "example.com:8443".parse().unwrap());
+        headers.insert("x-forwarded-for", "1.2.3.4".parse().unwrap());
+        headers.insert("x-forwarded-proto", "http".parse().unwrap());
+        headers.insert("forwarded", "for=1.2.3.4".parse().unwrap());
+        headers
+    };
+    let mut headers = incoming();
+    both.apply(&mut headers, &forwarding, true);
+    assert_eq!(headers["x-forwarded-for"], "1.2.3.4, 192.168.0.1");
+    assert_eq!(headers["x-forwarded-proto"], "http");
+    assert_eq!(headers["x-forwarded-port"], "443");
+    assert_eq!(
+        headers["forwarded"],
+        "for=1.2.3.4, for=192.168.0.1;proto=https;host=\"example.com:8443\""
+    );
+    let mut headers = incoming();
+    both.apply(&mut headers, &forwarding, false);
+    assert_eq!(headers["x-forwarded-for"], "192.168.0.1");
+    assert_eq!(headers["x-forwarded-proto"], "https");
+    assert_eq!(
+        headers["forwarded"],
+        "for=192.168.0.1;proto=https;host=\"example.com:8443\""
+    );
+    // only the enabled headers are touched
+    let mut headers = incoming();
+    let remote_address = SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 4000));
+    let forwarding = Forwarding {
+        remote_address: &remote_address,
+        local_address: None,
+        tls: false,
+    };
+    let forwarded = ForwardedHeaders {
+        x_forwarded: false,
+        forwarded: true,
+    };
+    forwarded.apply(&mut headers, &forwarding, false);
+    assert_eq!(headers["x-forwarded-for"], "1.2.3.4");
+    assert_eq!(
+        headers["forwarded"],
+        "for=\"[2001:db8::1]\";proto=http;host=\"example.com:8443\""
+    );
+    assert!(!headers.contains_key("x-forwarded-port"));
+}
+
+#[test]
+fn test_http_upgrade() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .upgrade_idle_timeout(Duration::from_millis(200))
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        let backend = listen().await;
+        conf.add_backend(backend.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = backend.accept().await {
+                tokio::spawn(async move {
+                    let request = read_head(&mut stream).await;
+                    assert!(request.contains("upgrade: echo\r\n"));
+                    stream
+                        .write_all(
+                            b"HTTP/1.1 101 Switching Protocols\r\n\
+                              connection: upgrade\r\nupgrade: echo\r\n\r\n",
+                        )
+                        .await
+                        .unwrap();
+                    let (mut read, mut write) = stream.split();
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let mut stream = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port)))
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET /chat HTTP/1.1\r\nhost: localhost\r\nconnection: Upgrade\r\nupgrade: echo\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_head(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("upgrade: echo\r\n"));
+        for message in [&b"ping"[..], &b"pong"[..]] {
+            stream.write_all(message).await.unwrap();
+            let mut echo = [0u8; 4];
+            stream.read_exact(&mut echo).await.unwrap();
+            assert_eq!(&echo, message);
+        }
+        // closed once idle
+        let mut buf = [0u8; 1];
+        let closed = timeout(Duration::from_millis(1000), stream.read(&mut buf)).await;
+        assert_eq!(closed.unwrap().unwrap(), 0);
+    });
+}
+
+#[test]
+fn test_http2_grpc() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .tls_certificate(
+                &["localhost"],
+                cert_path("localhost.pem"),
+                cert_path("localhost.key"),
+            )
+            .backend_http2()
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        for name in ["first", "second"] {
+            conf.add_backend(grpc_backend(name).await);
+        }
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let stream = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port)))
+            .await
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(cert_path("ca.pem")).unwrap())
+            .unwrap();
+        let mut config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+        let (sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let call = |path: &str| {
+            let request = http::Request::builder()
+                .method("POST")
+                .uri(format!("https://localhost{}", path))
+                .header("content-type", "application/grpc")
+                .header("te", "trailers")
+                .body(String::from("request"))
+                .unwrap();
+            let mut sender = sender.clone();
+            async move {
+                let response = sender.send_request(request).await?;
+                response.into_body().collect().await
+            }
+        };
+        // the concurrent streams of the connection go to different backends, the least loaded
+        // one each time
+        let (first, second) = tokio::join!(call("/Service/Method"), call("/Service/Method"));
+        let mut names = vec![];
+        for response in [first.unwrap(), second.unwrap()] {
+            assert_eq!(response.trailers().unwrap()["grpc-status"], "0");
+            names.push(String::from_utf8(response.to_bytes().to_vec()).unwrap());
+        }
+        names.sort();
+        assert_eq!(names, ["first", "second"]);
+        // a stream reset by the backend is reset for the client
+        let reset = call("/Service/Reset").await.unwrap_err();
+        assert!(format!("{:?}", reset).contains("Reset"), "{:?}", reset);
+        assert!(call("/Service/Method").await.is_ok());
+    });
+}
+
+#[test]
+fn test_http_cancelled_request() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .backend_http2()
+            .max_connections_per_backend(1)
+            .queue_timeout(Duration::from_millis(100))
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        conf.add_backend(grpc_backend("only").await);
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let stream = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port)))
+            .await
+            .unwrap();
+        let (sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let call = || {
+            let request = http::Request::builder()
+                .method("POST")
+                .uri("http://localhost/Service/Method")
+                .header("content-type", "application/grpc")
+                .header("te", "trailers")
+                .body(String::from("request"))
+                .unwrap();
+            let mut sender = sender.clone();
+            async move {
+                let response = sender.send_request(request).await?;
+                response.into_body().collect().await
+            }
+        };
+        // the call is cancelled while the backend takes its only slot, which is given back
+        assert!(timeout(Duration::from_millis(50), call()).await.is_err());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let response = call().await.unwrap();
+        assert_eq!(response.trailers().unwrap()["grpc-status"], "0");
+        assert_eq!(response.to_bytes(), "only");
+    });
+}
+
+#[test]
+fn test_sticky_cookie() {
+    let sticky = StickyCookie::new("backend", b"secret");
+    let first = SocketAddr::from(([10, 0, 0, 1], 8080));
+    let second = SocketAddr::from(([10, 0, 0, 2], 8080));
+    let token = sticky.token(&first);
+    assert!(!token.contains("10.0.0.1"));
+    assert!(sticky.matches(&token, &first));
+    assert!(!sticky.matches(&token, &second));
+    assert!(!sticky.matches(&token[1..], &first));
+    assert!(!sticky.matches("not hex", &first));
+    assert!(!StickyCookie::new("backend", b"other secret").matches(&token, &first));
+    let request = http::Request::builder()
+        .header("cookie", "a=1; backend=abc")
+        .header("cookie", "b=2")
+        .body(())
+        .unwrap()
+        .into_parts()
+        .0;
+    assert_eq!(sticky.value(&request), Some("abc"));
+    assert_eq!(StickyCookie::new("b", b"").value(&request), Some("2"));
+    assert_eq!(StickyCookie::new("c", b"").value(&request), None);
+}
+
+#[test]
+fn test_http_sticky_sessions() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .sticky_cookie("backend", b"secret")
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        let mut backends = vec![];
+        for name in ["first", "second"] {
+            let (backend, _) = http_backend(name).await;
+            conf.add_backend(backend);
+            backends.push(backend);
+        }
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let mut sender = http_client(SocketAddr::from(([127, 0, 0, 1], port))).await;
+        let mut get = async |cookie: Option<String>| {
+            let mut request = http::Request::builder()
+                .uri("/")
+                .header("host", "localhost");
+            if let Some(cookie) = cookie {
+                request = request.header("cookie", cookie);
+            }
+            let request = request.body(String::new()).unwrap();
+            sender.ready().await.unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            let set_cookie = response
+                .headers()
+                .get("set-cookie")
+                .map(|it| it.to_str().unwrap().split(';').next().unwrap().to_string());
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (String::from_utf8(body.to_vec()).unwrap(), set_cookie)
+        };
+        let (body, cookie) = get(None).await;
+        assert_eq!(body, "first /");
+        let first = cookie.unwrap();
+        assert_eq!(
+            get(Some(first.clone())).await,
+            ("first /".to_string(), None)
+        );
+        // the client moves to another backend, and sticks to it
+        conf.set_backend_available(backends[0], false);
+        let (body, cookie) = get(Some(first.clone())).await;
+        assert_eq!(body, "second /");
+        let second = cookie.unwrap();
+        conf.set_backend_available(backends[0], true);
+        assert_eq!(
+            get(Some(second.clone())).await,
+            ("second /".to_string(), None)
+        );
+        assert_eq!(get(Some(first)).await, ("first /".to_string(), None));
+        // forged or stale cookies are replaced
+        let (body, cookie) = get(Some("backend=00".to_string())).await;
+        assert_eq!((body.as_str(), cookie.is_some()), ("first /", true));
+        conf.remove_backend(backends[1]);
+        let (body, cookie) = get(Some(second)).await;
+        assert_eq!((body.as_str(), cookie.is_some()), ("first /", true));
+    });
+}
+
+#[test]
+fn test_http_error_responses() {
+    let directory = std::env::temp_dir().join(format!("headmaster-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("503.html"), "<h1>maintenance</h1>").unwrap();
+    std::fs::write(directory.join("502.json"), r#"{"error":"bad gateway"}"#).unwrap();
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .error_response(
+                Failure::NoBackend,
+                ErrorResponseSettings {
+                    body_path: directory.join("503.html"),
+                    content_type: "text/html".to_string(),
+                    retry_after: Some(Duration::from_secs(30)),
+                },
+            )
+            .error_response(
+                Failure::ConnectionFailure,
+                ErrorResponseSettings {
+                    body_path: directory.join("502.json"),
+                    content_type: "application/json".to_string(),
+                    retry_after: None,
+                },
+            )
+            .build()
+    });
+    static MISSING: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let missing = MISSING.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .error_response(
+                Failure::QueueFull,
+                ErrorResponseSettings {
+                    body_path: directory.join("missing.html"),
+                    content_type: "text/html".to_string(),
+                    retry_after: None,
+                },
+            )
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        // the body files are read when binding
+        assert!(bind(missing).await.is_err());
+        let listener = bind(conf).await.unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let mut sender = http_client(SocketAddr::from(([127, 0, 0, 1], port))).await;
+        let mut get = async || {
+            let request = http::Request::builder()
+                .uri("/")
+                .header("host", "www.example.com")
+                .body(String::new())
+                .unwrap();
+            sender.ready().await.unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            let (parts, body) = response.into_parts();
+            let header = |name| {
+                parts
+                    .headers
+                    .get(name)
+                    .map(|it: &HeaderValue| it.to_str().unwrap().to_string())
+            };
+            let headers = (header("content-type"), header("retry-after"));
+            let body = body.collect().await.unwrap().to_bytes();
+            (parts.status, headers, body)
+        };
+        let (status, headers, body) = get().await;
+        assert_eq!(status, 503);
+        assert_eq!(
+            headers,
+            (Some("text/html".to_string()), Some("30".to_string()))
+        );
+        assert_eq!(body, "<h1>maintenance</h1>".as_bytes());
+        // nothing listens on the backend address
+        let backend = listen().await.local_addr().unwrap();
+        conf.add_backend(backend);
+        let (status, headers, body) = get().await;
+        assert_eq!(status, 502);
+        assert_eq!(headers, (Some("application/json".to_string()), None));
+        assert_eq!(body, r#"{"error":"bad gateway"}"#.as_bytes());
+    });
+}
+
+#[test]
+fn test_header_rules() {
+    let request = |method: &str, host: &str, path: &str| {
+        http::Request::builder()
+            .method(method)
+            .uri(path)
+            .header("host", host)
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    };
+    let client = SocketAddr::from(([10, 1, 2, 3], 4000));
+    let set = HeaderAction::Set(
+        HeaderName::from_static("x-a"),
+        HeaderValue::from_static("1"),
+    );
+    let rule = HeaderRule::request(set.clone())
+        .host("*.example.com")
+        .path_prefix("/api/")
+        .method(http::Method::POST)
+        .client("10.0.0.0/8".parse().unwrap());
+    assert!(rule.matches(&client, &request("POST", "www.example.com", "/api/a")));
+    assert!(!rule.matches(&client, &request("GET", "www.example.com", "/api/a")));
+    assert!(!rule.matches(&client, &request("POST", "example.com", "/api/a")));
+    assert!(!rule.matches(&client, &request("POST", "www.example.com", "/a")));
+    let other = SocketAddr::from(([192, 168, 0, 1], 4000));
+    assert!(!rule.matches(&other, &request("POST", "www.example.com", "/api/a")));
+    assert!(HeaderRule::response(set).matches(&other, &request("GET", "a", "/")));
+    let mut headers = http::HeaderMap::new();
+    headers.append("x-a", "0".parse().unwrap());
+    headers.append("x-a", "2".parse().unwrap());
+    headers.append("location", "http://backend:8080/a".parse().unwrap());
+    headers.append("location", "/b".parse().unwrap());
+    let name = |it| HeaderName::from_static(it);
+    let value = |it| HeaderValue::from_static(it);
+    for action in [
+        HeaderAction::Add(name("x-b"), value("1")),
+        HeaderAction::Add(name("x-b"), value("2")),
+        HeaderAction::Set(name("x-a"), value("1")),
+        HeaderAction::Remove(name("x-c")),
+        HeaderAction::Replace(
+            name("location"),
+            Regex::new(r"^http://backend:8080(/.*)$").unwrap(),
+            "https://www.example.com$1".to_string(),
+        ),
+    ] {
+        HeaderRule::request(action).apply(&mut headers);
+    }
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|it| it.to_str().unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(values("x-a"), ["1"]);
+    assert_eq!(values("x-b"), ["1", "2"]);
+    assert_eq!(values("location"), ["https://www.example.com/a", "/b"]);
+}
+
+#[test]
+fn test_http_header_rewrite() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .header_rule(HeaderRule::response(HeaderAction::Remove(
+                HeaderName::from_static("x-internal"),
+            )))
+            .header_rule(HeaderRule::response(HeaderAction::Set(
+                HeaderName::from_static("strict-transport-security"),
+                HeaderValue::from_static("max-age=31536000"),
+            )))
+            .http_route(
+                HttpRoute::to_pool("api")
+                    .path_prefix("/api/")
+                    .header_rule(HeaderRule::request(HeaderAction::Set(
+                        HeaderName::from_static("x-env"),
+                        HeaderValue::from_static("api"),
+                    )))
+                    .header_rule(
+                        HeaderRule::request(HeaderAction::Remove(HeaderName::from_static("x-env")))
+                            .method(http::Method::DELETE),
+                    ),
+            )
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        // answers with the x-env header of the request, and an internal header
+        let listener = listen().await;
+        let backend = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let service = service_fn(|request: http::Request<Incoming>| async move {
+                        let env = request
+                            .headers()
+                            .get("x-env")
+                            .map(|it| it.to_str().unwrap().to_string())
+                            .unwrap_or_default();
+                        let response = http::Response::builder()
+                            .header("x-internal", "secret")
+                            .body(env)
+                            .unwrap();
+                        Ok::<_, std::convert::Infallible>(response)
+                    });
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        assert!(conf.add_pool_backend("api", backend));
+        conf.add_backend(backend);
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let mut sender = http_client(SocketAddr::from(([127, 0, 0, 1], port))).await;
+        for (method, path, expected) in [
+            ("GET", "/api/a", "api"),
+            ("DELETE", "/api/a", ""),
+            ("GET", "/a", "client"),
+        ] {
+            let request = http::Request::builder()
+                .method(method)
+                .uri(path)
+                .header("host", "www.example.com")
+                .header("x-env", "client")
+                .body(String::new())
+                .unwrap();
+            sender.ready().await.unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            assert!(!response.headers().contains_key("x-internal"));
+            assert_eq!(
+                response.headers()["strict-transport-security"],
+                "max-age=31536000"
+            );
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, expected.as_bytes());
+        }
+        // the responses made by headmaster get them too
+        conf.remove_backend(backend);
+        let request = http::Request::builder()
+            .uri("/a")
+            .header("host", "www.example.com")
+            .body(String::new())
+            .unwrap();
+        sender.ready().await.unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), 503);
+        assert!(response.headers().contains_key("strict-transport-security"));
+    });
+}
+
+#[test]
+fn test_url_rules() {
+    let request = |host: &str, uri: &str| {
+        http::Request::builder()
+            .uri(uri)
+            .header("host", host)
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    };
+    let https = UrlRule::redirect(
+        http::StatusCode::MOVED_PERMANENTLY,
+        "https://{host}{path}{query}",
+    )
+    .plaintext();
+    let canonical = UrlRule::redirect(
+        http::StatusCode::PERMANENT_REDIRECT,
+        "https://www.example.com{path}{query}",
+    )
+    .host("example.com");
+    let moved = UrlRule::redirect(http::StatusCode::FOUND, "/new/$1")
+        .path_regex(Regex::new(r"^/old/(.*)$").unwrap());
+    let api = UrlRule::rewrite("/v2/$rest").path_regex(Regex::new(r"^/api/(?P<rest>.*)$").unwrap());
+    let search = UrlRule::rewrite("/search?q=$1").path_regex(Regex::new(r"^/s/(\w+)$").unwrap());
+    let rules = [&https, &canonical, &moved, &api, &search];
+    let mut parts = request("www.example.com:80", "/a?b=c");
+    assert_eq!(
+        rewrite::apply(&rules, &mut parts, false),
+        Outcome::Redirect(
+            http::StatusCode::MOVED_PERMANENTLY,
+            "https://www.example.com/a?b=c".to_string()
+        )
+    );
+    assert_eq!(
+        rewrite::apply(&rules, &mut request("example.com", "/a"), true),
+        Outcome::Redirect(
+            http::StatusCode::PERMANENT_REDIRECT,
+            "https://www.example.com/a".to_string()
+        )
+    );
+    assert_eq!(
+        rewrite::apply(&rules, &mut request("www.example.com", "/old/a/b"), true),
+        Outcome::Redirect(http::StatusCode::FOUND, "/new/a/b".to_string())
+    );
+    let mut parts = request("www.example.com", "/api/users?page=2");
+    assert_eq!(rewrite::apply(&rules, &mut parts, true), Outcome::Forward);
+    assert_eq!(parts.uri, "/v2/users?page=2");
+    let mut parts = request("www.example.com", "/s/rust?page=2");
+    assert_eq!(rewrite::apply(&rules, &mut parts, true), Outcome::Forward);
+    assert_eq!(parts.uri, "/search?q=rust");
+    // the next rules see the rewritten request
+    let mut parts = request("www.example.com", "/api/old/a");
+    let rules = [
+        &api,
+        &UrlRule::rewrite("/v3/$1").path_regex(Regex::new("^/v2/(.*)$").unwrap()),
+    ];
+    assert_eq!(rewrite::apply(&rules, &mut parts, true), Outcome::Forward);
+    assert_eq!(parts.uri, "/v3/old/a");
+}
+
+#[test]
+fn test_http_redirects() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .url_rule(
+                UrlRule::redirect(
+                    http::StatusCode::MOVED_PERMANENTLY,
+                    "https://www.example.com{path}{query}",
+                )
+                .host("example.com"),
+            )
+            .http_route(
+                HttpRoute::to_pool("legacy")
+                    .path_prefix("/legacy/")
+                    .url_rule(
+                        UrlRule::redirect(http::StatusCode::TEMPORARY_REDIRECT, "/docs/$1")
+                            .path_regex(Regex::new(r"^/legacy/docs/(.*)$").unwrap()),
+                    )
+                    .url_rule(
+                        UrlRule::rewrite("/api/$1")
+                            .path_regex(Regex::new(r"^/legacy/(.*)$").unwrap()),
+                    ),
+            )
+            .http_route(HttpRoute::to_pool("api").path_prefix("/api/"))
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        let (default, _) = http_backend("default").await;
+        conf.add_backend(default);
+        let (api, _) = http_backend("api").await;
+        assert!(conf.add_pool_backend("api", api));
+        // the legacy pool has no backend, its requests are rewritten to the api
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let mut sender = http_client(SocketAddr::from(([127, 0, 0, 1], port))).await;
+        for (host, path, status, expected) in [
+            ("example.com", "/a?b", 301, "https://www.example.com/a?b"),
+            ("www.example.com", "/legacy/docs/a", 307, "/docs/a"),
+            ("www.example.com", "/legacy/users", 200, "api /api/users"),
+            ("www.example.com", "/users", 200, "default /users"),
+        ] {
+            let request = http::Request::builder()
+                .uri(path)
+                .header("host", host)
+                .body(String::new())
+                .unwrap();
+            sender.ready().await.unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            assert_eq!(response.status(), status);
+            if status == 200 {
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                assert_eq!(body, expected.as_bytes());
+            } else {
+                assert_eq!(response.headers()["location"], expected);
+            }
+        }
+    });
+}
+
+#[test]
+fn test_retries() {
+    let retries = Retries::new(RetrySettings {
+        budget_ratio: 0.5,
+        budget_max: 2.0,
+        ..RetrySettings::default()
+    });
+    let request = |method: &str, key: bool| {
+        let mut request = http::Request::builder().method(method).uri("/");
+        if key {
+            request = request.header("idempotency-key", "1");
+        }
+        request.body(()).unwrap().into_parts().0
+    };
+    assert!(retries.applies(&request("GET", false), Some(0)));
+    assert!(retries.applies(&request("PUT", false), Some(65_536)));
+    assert!(!retries.applies(&request("PUT", false), Some(65_537)));
+    assert!(!retries.applies(&request("PUT", false), None));
+    assert!(!retries.applies(&request("POST", false), Some(10)));
+    assert!(retries.applies(&request("POST", true), Some(10)));
+    // the budget starts full, and is refilled by the requests
+    assert!(retries.withdraw());
+    assert!(retries.withdraw());
+    assert!(!retries.withdraw());
+    retries.deposit();
+    assert!(!retries.withdraw());
+    retries.deposit();
+    assert!(retries.withdraw());
+    retries.refund();
+    for _ in 0..10 {
+        retries.deposit();
+    }
+    assert!(retries.withdraw());
+    assert!(retries.withdraw());
+    assert!(!retries.withdraw());
+}
+
+#[test]
+fn test_http_retries() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .retries(RetrySettings {
+                budget_ratio: 0.0,
+                budget_max: 2.0,
+                ..RetrySettings::default()
+            })
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        // always unavailable, and selected first
+        let listener = listen().await;
+        let unavailable = listener.local_addr().unwrap();
+        let rejected = Arc::new(AtomicUsize::new(0));
+        let counter = rejected.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |_: http::Request<Incoming>| {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        async move {
+                            let response = http::Response::builder()
+                                .status(503)
+                                .body("unavailable".to_string())
+                                .unwrap();
+                            Ok::<_, std::convert::Infallible>(response)
+                        }
+                    });
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        conf.add_backend(unavailable);
+        let (backend, _) = http_backend("second").await;
+        conf.add_backend(backend);
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let mut sender = http_client(SocketAddr::from(([127, 0, 0, 1], port))).await;
+        for (method, key, expected) in [
+            ("GET", false, "second /"),
+            // not idempotent
+            ("POST", false, "unavailable"),
+            ("POST", true, "second /"),
+            // no retry left in the budget
+            ("GET", false, "unavailable"),
+        ] {
+            let mut request = http::Request::builder()
+                .method(method)
+                .uri("/")
+                .header("host", "www.example.com");
+            if key {
+                request = request.header("idempotency-key", "1");
+            }
+            let request = request.body("body".to_string()).unwrap();
+            sender.ready().await.unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, expected.as_bytes());
+        }
+        assert_eq!(rejected.load(Ordering::SeqCst), 4);
+    });
+}
+
+#[test]
+fn test_compression_settings() {
+    let settings = CompressionSettings::default();
+    let request = |method: &str, accept_encoding: &str| {
+        http::Request::builder()
+            .method(method)
+            .uri("/")
+            .header("accept-encoding", accept_encoding)
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    };
+    let response = |status: u16, headers: &[(&str, &str)]| {
+        let mut response = http::Response::builder().status(status);
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+        response.body(()).unwrap().into_parts().0
+    };
+    let json = response(200, &[("content-type", "application/json")]);
+    for (accept_encoding, expected) in [
+        ("gzip", Some(Encoding::Gzip)),
+        ("gzip, br", Some(Encoding::Brotli)),
+        ("br;q=0.5, gzip", Some(Encoding::Gzip)),
+        ("*", Some(Encoding::Brotli)),
+        ("br;q=0, gzip;q=0", None),
+        ("identity", None),
+    ] {
+        let request = request("GET", accept_encoding);
+        assert_eq!(settings.encoding(&request, &json), expected);
+    }
+    let gzip = request("GET", "gzip");
+    let html = response(200, &[("content-type", "text/html; charset=utf-8")]);
+    assert_eq!(settings.encoding(&gzip, &html), Some(Encoding::Gzip));
+    for headers in [
+        &[("content-type", "image/png")][..],
+        &[
+            ("content-type", "application/json"),
+            ("content-length", "1023"),
+        ],
+        &[
+            ("content-type", "application/json"),
+            ("content-encoding", "gzip"),
+        ],
+        &[
+            ("content-type", "application/json"),
+            ("cache-control", "public, no-transform"),
+        ],
+        &[],
+    ] {
+        assert_eq!(settings.encoding(&gzip, &response(200, headers)), None);
+    }
+    assert_eq!(
+        settings.encoding(&gzip, &response(206, &[("content-type", "text/html")])),
+        None
+    );
+    assert_eq!(settings.encoding(&request("HEAD", "gzip"), &json), None);
+}
+
+#[test]
+fn test_http_compression() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .compression(CompressionSettings {
+                min_size: 100,
+                ..CompressionSettings::default()
+            })
+            .http_route(HttpRoute::to_pool("stream").path_prefix("/stream"))
+            .build()
+    });
+    let json = format!("[{}0]", "0,".repeat(1000));
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        let listener = listen().await;
+        let backend = listener.local_addr().unwrap();
+        let body = json.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |request: http::Request<Incoming>| {
+                        let (content_type, body) = match request.uri().path() {
+                            "/small" => ("application/json", "[0]".to_string()),
+                            "/image" => ("image/png", body.clone()),
+                            _ => ("application/json", body.clone()),
+                        };
+                        let mut response = http::Response::builder()
+                            .header("content-type", content_type)
+                            .header("etag", "\"v1\"");
+                        if request.uri().path() == "/encoded" {
+                            response = response.header("content-encoding", "identity");
+                        }
+                        let response = response.body(body).unwrap();
+                        async move { Ok::<_, std::convert::Infallible>(response) }
+                    });
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        conf.add_backend(backend);
+        // sends the second chunk once the first one was received by the client
+        let listener = listen().await;
+        let stream_backend = listener.local_addr().unwrap();
+        let received = Arc::new(tokio::sync::Notify::new());
+        let notified = received.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_head(&mut stream).await;
+            let head =
+                "HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ntransfer-encoding: chunked\r\n\r\n";
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(b"6\r\nfirst \r\n").await.unwrap();
+            notified.notified().await;
+            stream.write_all(b"6\r\nsecond\r\n0\r\n\r\n").await.unwrap();
+        });
+        assert!(conf.add_pool_backend("stream", stream_backend));
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let mut sender = http_client(SocketAddr::from(([127, 0, 0, 1], port))).await;
+        let mut get = async |path: &str, accept_encoding: &str| {
+            let request = http::Request::builder()
+                .uri(path)
+                .header("host", "www.example.com")
+                .header("accept-encoding", accept_encoding)
+                .body(String::new())
+                .unwrap();
+            sender.ready().await.unwrap();
+            sender.send_request(request).await.unwrap()
+        };
+        let response = get("/", "gzip").await;
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.headers()["vary"], "accept-encoding");
+        assert_eq!(response.headers()["etag"], "W/\"v1\"");
+        assert!(!response.headers().contains_key("content-length"));
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.len() < json.len());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, json);
+        let response = get("/", "gzip, br").await;
+        assert_eq!(response.headers()["content-encoding"], "br");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&body[..], 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, json);
+        for (path, accept_encoding) in [
+            ("/", "identity"),
+            ("/small", "gzip"),
+            ("/image", "gzip"),
+            ("/encoded", "gzip"),
+        ] {
+            let response = get(path, accept_encoding).await;
+            let encoding = response.headers().get("content-encoding").cloned();
+            assert!(encoding.map(|it| it == "identity").unwrap_or(true));
+            assert_eq!(response.headers()["etag"], "\"v1\"");
+        }
+        // each chunk is sent compressed as soon as it is received
+        let response = get("/stream", "gzip").await;
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        let mut body = response.into_body();
+        let mut decoder = flate2::write::GzDecoder::new(vec![]);
+        let frame = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        decoder.write_all(&frame).unwrap();
+        decoder.flush().unwrap();
+        assert_eq!(decoder.get_ref().as_slice(), b"first ");
+        received.notify_one();
+        let rest = body.collect().await.unwrap().to_bytes();
+        decoder.write_all(&rest).unwrap();
+        assert_eq!(decoder.finish().unwrap(), b"first second");
+    });
+}
+
+#[test]
+fn test_cache() {
+    let cache: &'static Cache = Box::leak(Box::new(Cache::new(CacheSettings {
+        max_size: 2500,
+        max_response_size: 1000,
+    })));
+    let request = |path: &str| {
+        http::Request::builder()
+            .uri(path)
+            .header("host", "a")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    };
+    let sharded: &'static Cache = Box::leak(Box::new(Cache::new(CacheSettings {
+        max_size: 1_000_000,
+        max_response_size: 1000,
+    })));
+    let runtime = runtime(1);
+    runtime.block_on(async move {
+        let store_in = async |cache: &'static Cache, path: &str, size: usize| {
+            let request = request(path);
+            let (parts, body) = http::Response::builder()
+                .header("cache-control", "max-age=60")
+                .body(http_body_util::Full::new(bytes::Bytes::from(vec![0; size])))
+                .unwrap()
+                .into_parts();
+            let body = body.map_err(|e| match e {}).boxed();
+            let key = Cache::key(&request, false).unwrap();
+            let body = cache.store(key, &request, &parts, body);
+            body.collect().await.unwrap();
+        };
+        let store = async |path: &str, size: usize| store_in(cache, path, size).await;
+        let fresh = |path: &str| {
+            let request = request(path);
+            matches!(
+                cache.lookup(&Cache::key(&request, false).unwrap(), &request),
+                Lookup::Fresh(_)
+            )
+        };
+        store("/1", 1000).await;
+        store("/2", 1000).await;
+        store("/big", 1001).await;
+        assert!(fresh("/1") && fresh("/2") && !fresh("/big"));
+        // not for the clients of the TLS listener
+        let tls_request = request("/1");
+        let tls_key = Cache::key(&tls_request, true).unwrap();
+        assert_eq!(tls_key, "https://a/1");
+        assert!(matches!(cache.lookup(&tls_key, &tls_request), Lookup::Miss));
+        // the least recently used one is evicted
+        assert!(fresh("/1"));
+        store("/3", 1000).await;
+        assert!(fresh("/1") && !fresh("/2") && fresh("/3"));
+        assert!(cache.size() <= 2500);
+        assert_eq!(cache.purge("http://a/3"), 1);
+        assert_eq!(cache.purge("http://a/3"), 0);
+        store("/2", 10).await;
+        assert_eq!(cache.purge_prefix("http://a/"), 2);
+        assert_eq!(cache.size(), 0);
+
+        // split into shards, purged together
+        for i in 0..100 {
+            store_in(sharded, &format!("/{}", i), 1000).await;
+        }
+        assert!(sharded.size() > 100_000);
+        assert_eq!(sharded.purge("http://a/7"), 1);
+        assert_eq!(sharded.purge_prefix("http://a/"), 99);
+        assert_eq!(sharded.size(), 0);
+    });
+}
+
+#[test]
+fn test_http_cache() {
+    static CONF: std::sync::OnceLock<ConfImpl> = std::sync::OnceLock::new();
+    let conf = CONF.get_or_init(|| {
+        ConfBuilder::new(BindAddress::TcpSocket(*ADDRESS))
+            .protocol(Protocol::Http)
+            .cache(CacheSettings::default())
+            .build()
+    });
+    let runtime = runtime(2);
+    runtime.block_on(async move {
+        let listener = listen().await;
+        let backend = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |request: http::Request<Incoming>| {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        let header = |name| {
+                            request
+                                .headers()
+                                .get(name)
+                                .map(|it: &HeaderValue| it.to_str().unwrap().to_string())
+                        };
+                        let response = http::Response::builder();
+                        let response = match request.uri().path() {
+                            "/fresh" => response
+                                .header("cache-control", "max-age=60")
+                                .header("etag", "\"f\"")
+                                .body("fresh".to_string()),
+                            "/private" => response
+                                .header("cache-control", "private, max-age=60")
+                                .body("private".to_string()),
+                            "/validated" if header("if-none-match").is_some() => response
+                                .status(304)
+                                .header("etag", "\"v\"")
+                                .body(String::new()),
+                            "/validated" => response
+                                .header("cache-control", "no-cache")
+                                .header("etag", "\"v\"")
+                                .body("validated".to_string()),
+                            _ => response
+                                .header("cache-control", "max-age=60")
+                                .header("vary", "accept-language")
+                                .body(header("accept-language").unwrap_or_default()),
+                        };
+                        async move { Ok::<_, std::convert::Infallible>(response.unwrap()) }
+                    });
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        conf.add_backend(backend);
+        let listener = bind(conf).await.unwrap();
+        let port = match listener {
+            SocketListener::Tcp(ref listener) => listener.local_addr().unwrap().port(),
+        };
+        tokio::spawn(accept_loop(conf, listener));
+        let mut sender = http_client(SocketAddr::from(([127, 0, 0, 1], port))).await;
+        let mut get = async |method: &str, path: &str, headers: &[(&str, &str)]| {
+            let mut request = http::Request::builder()
+                .method(method)
+                .uri(path)
+                .header("host", "www.example.com");
+            for (name, value) in headers {
+                request = request.header(*name, *value);
+            }
+            sender.ready().await.unwrap();
+            let response = sender
+                .send_request(request.body(String::new()).unwrap())
+                .await
+                .unwrap();
+            let status = response.status().as_u16();
+            let age = response.headers().contains_key("age");
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, String::from_utf8(body.to_vec()).unwrap(), age)
+        };
+        let count = || requests.load(Ordering::SeqCst);
+        assert_eq!(
+            get("GET", "/fresh", &[]).await,
+            (200, "fresh".to_string(), false)
+        );
+        assert_eq!(
+            get("GET", "/fresh", &[]).await,
+            (200, "fresh".to_string(), true)
+        );
+        assert_eq!(get("HEAD", "/fresh", &[]).await, (200, String::new(), true));
+        let not_modified = get("GET", "/fresh", &[("if-none-match", "\"f\"")]).await;
+        assert_eq!(not_modified, (304, String::new(), true));
+        assert_eq!(count(), 1);
+        // the client asks for a fresh response
+        get("GET", "/fresh", &[("cache-control", "no-cache")]).await;
+        assert_eq!(count(), 2);
+        get("GET", "/private", &[]).await;
+        get("GET", "/private", &[]).await;
+        assert_eq!(count(), 4);
+        // stale, but still valid
+        get("GET", "/validated", &[]).await;
+        assert_eq!(
+            get("GET", "/validated", &[]).await,
+            (200, "validated".to_string(), true)
+        );
+        assert_eq!(count(), 6);
+        // the response of the backend to a conditional request of the client is sent as is
+        let not_modified = get("GET", "/validated", &[("if-none-match", "\"v\"")]).await;
+        assert_eq!(not_modified, (304, String::new(), false));
+        assert_eq!(count(), 7);
+        for language in ["en", "fr", "en", "fr"] {
+            let response = get("GET", "/vary", &[("accept-language", language)]).await;
+            assert_eq!(response.1, language);
+        }
+        assert_eq!(count(), 9);
+        let cache = conf.cache().unwrap();
+        assert_eq!(cache.purge("http://www.example.com/vary"), 2);
+        assert_eq!(cache.purge_prefix("http://www.example.com/"), 2);
+        get("GET", "/fresh", &[]).await;
+        assert_eq!(count(), 10);
+    });
+}
+
+// Answers every request with the name of the backend and the path, counting the connections.
+async fn http_backend(name: &'static str) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = listen().await;
+    let address = listener.local_addr().unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let service = service_fn(move |request: http::Request<Incoming>| async move {
+                    let body = format!("{} {}", name, request.uri().path());
+                    Ok::<_, std::convert::Infallible>(http::Response::new(body))
+                });
+                let _ = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    (address, accepted)
+}
+
+async fn http_client(address: SocketAddr) -> hyper::client::conn::http1::SendRequest<String> {
+    let stream = TcpStream::connect(address).await.unwrap();
+    let (sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+    sender
+}
+
+// HTTP/2 backend answering with its name and a gRPC status, after a while so that concurrent
+// calls need different backends. The "Reset" method resets the stream.
+async fn grpc_backend(name: &'static str) -> SocketAddr {
+    let listener = listen().await;
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let service = service_fn(move |request: http::Request<Incoming>| async move {
+                    assert_eq!(request.headers()["te"], "trailers");
+                    if request.uri().path() == "/Service/Reset" {
+                        return Err("reset");
+                    }
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    let mut trailers = http::HeaderMap::new();
+                    trailers.insert("grpc-status", "0".parse().unwrap());
+                    let body = http_body_util::Full::new(bytes::Bytes::from(name))
+                        .with_trailers(async move { Some(Ok(trailers)) });
+                    Ok(http::Response::new(body))
+                });
+                let _ = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    address
+}
+
+// Reads up to the end of the headers of an HTTP message.
+async fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    String::from_utf8(head).unwrap()
+}
+
+fn cert_path(name: &str) -> String {
+    format!("{}/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_file(cert_path("ca.pem")).unwrap())
+        .unwrap();
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+fn client_hello(server_name: &str, alpn_protocols: &[&[u8]]) -> Vec<u8> {
+    let mut config = ClientConfig::builder()
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth();
+    config.alpn_protocols = alpn_protocols.iter().map(|it| it.to_vec()).collect();
+    let mut connection = ClientConnection::new(
+        Arc::new(config),
+        ServerName::try_from(server_name.to_string()).unwrap(),
+    )
+    .unwrap();
+    let mut bytes = vec![];
+    connection.write_tls(&mut bytes).unwrap();
+    bytes
+}
+
 fn runtime(thread_count: usize) -> Runtime {
     tokio::runtime::Builder::new_multi_thread()
         .worker_threads(thread_count)